@@ -1,12 +1,79 @@
+use chrono::NaiveTime;
 use log::debug;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+
+use crate::wallhaven::{Categories, FileType, SearchQuery};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    Invalid(Vec<String>),
+}
+
+impl ConfigError {
+    pub(crate) fn parse(path: &Path, contents: &str, err: &toml::de::Error) -> Self {
+        let offset = err.span().map_or(0, |span| span.start);
+        Self::at(path, contents, offset, err.message().trim().to_string())
+    }
+
+    /// A problem at byte `offset` of the file at `path`.
+    pub(crate) fn at(path: &Path, contents: &str, offset: usize, message: String) -> Self {
+        let before = &contents[..offset.min(contents.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        ConfigError::Parse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "Could not read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct General {
+    #[serde(default)]
     pub wallpaper_dir: String,
-    pub purity: Option<String>,
+    /// Which wallpapers `refresh` picks from
+    #[serde(default)]
+    pub purity: Purity,
+    #[serde(default)]
     pub wallpaper_app: String,
 }
 
@@ -14,12 +81,113 @@ impl Default for General {
     fn default() -> Self {
         Self {
             wallpaper_dir: "".to_string(),
-            purity: Some("sfw".to_string()),
+            purity: Purity::default(),
             wallpaper_app: "".to_string(),
         }
     }
 }
 
+/// Content rating of a single wallpaper, as Wallhaven names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PurityLevel {
+    Sfw,
+    Sketchy,
+    Nsfw,
+}
+
+impl PurityLevel {
+    pub const ALL: [PurityLevel; 3] = [PurityLevel::Sfw, PurityLevel::Sketchy, PurityLevel::Nsfw];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PurityLevel::Sfw => "sfw",
+            PurityLevel::Sketchy => "sketchy",
+            PurityLevel::Nsfw => "nsfw",
+        }
+    }
+
+    /// Sub directory of the wallpaper dir downloads of this level go into.
+    pub fn dir(self) -> Option<&'static str> {
+        match self {
+            PurityLevel::Sfw => None,
+            PurityLevel::Sketchy | PurityLevel::Nsfw => Some("nsfw"),
+        }
+    }
+}
+
+/// A set of purity levels, written as names ("sfw,sketchy") or as a Wallhaven
+/// bitmask ("110").
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Purity([bool; 3]);
+
+impl Purity {
+    pub const ALL: Purity = Purity([true; 3]);
+
+    pub fn contains(self, level: PurityLevel) -> bool {
+        self.0[level as usize]
+    }
+
+    /// The `purity` parameter of the Wallhaven search API.
+    pub fn bitmask(self) -> String {
+        self.0
+            .iter()
+            .map(|&on| if on { '1' } else { '0' })
+            .collect()
+    }
+}
+
+impl Default for Purity {
+    fn default() -> Self {
+        Purity([true, false, false])
+    }
+}
+
+impl TryFrom<String> for Purity {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let mut levels = [false; 3];
+        if value.len() == 3 && value.chars().all(|c| c == '0' || c == '1') {
+            for (level, c) in levels.iter_mut().zip(value.chars()) {
+                *level = c == '1';
+            }
+        } else {
+            for name in value.split([',', '+', ' ']).filter(|name| !name.is_empty()) {
+                let level = PurityLevel::from_name(name).ok_or_else(|| {
+                    format!(
+                        "Invalid purity '{}', expected sfw, sketchy, nsfw or a bitmask like 110",
+                        name
+                    )
+                })?;
+                levels[level as usize] = true;
+            }
+        }
+        if !levels.contains(&true) {
+            return Err(format!("Purity '{}' does not allow any wallpaper", value));
+        }
+        Ok(Purity(levels))
+    }
+}
+
+impl std::fmt::Display for Purity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<_> = PurityLevel::ALL
+            .into_iter()
+            .filter(|level| self.contains(*level))
+            .map(PurityLevel::name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
 #[derive(Deserialize)]
 pub struct DatabaseConfig {
     pub database_path: String,
@@ -27,79 +195,1137 @@ pub struct DatabaseConfig {
 
 impl Default for DatabaseConfig {
     fn default() -> Self {
-        let mut config_path: PathBuf = dirs::home_dir().unwrap();
-        config_path.push(".local/share/applications/sinh-x/wallpaper/");
-
         Self {
-            database_path: config_path.to_str().unwrap().to_string(),
+            database_path: default_database_path().display().to_string(),
         }
     }
 }
 
-#[derive(Deserialize)]
-pub struct Swww {}
+/// `$XDG_CONFIG_HOME/sinh-x/wallpaper/config.toml`, used unless `--config` or
+/// SINH_WALLPAPER_CONFIG name another file.
+pub fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join(".config"))
+        .join("sinh-x/wallpaper/config.toml")
+}
 
-#[derive(Deserialize)]
-pub struct Feh {}
+/// `$XDG_DATA_HOME/sinh-x/wallpaper`, or the directory earlier versions used if
+/// only that one exists.
+pub fn default_database_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    let path = dirs::data_dir()
+        .unwrap_or_else(|| home.join(".local/share"))
+        .join("sinh-x/wallpaper");
+    let legacy = home.join(".local/share/applications/sinh-x/wallpaper");
+    if !path.exists() && legacy.exists() {
+        legacy
+    } else {
+        path
+    }
+}
+
+/// `$XDG_DATA_HOME/sinh-x/wallpaper.log`, next to the database.
+pub fn default_log_path() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join(".local/share"))
+        .join("sinh-x/wallpaper.log")
+}
+
+const SWWW_TRANSITIONS: [&str; 14] = [
+    "none", "simple", "fade", "left", "right", "top", "bottom", "wipe", "wave", "grow", "center",
+    "any", "outer", "random",
+];
+const SWWW_POSITIONS: [&str; 9] = [
+    "center",
+    "top",
+    "left",
+    "right",
+    "bottom",
+    "top-left",
+    "top-right",
+    "bottom-left",
+    "bottom-right",
+];
+const SWWW_RESIZE: [&str; 4] = ["no", "crop", "fit", "stretch"];
+
+/// Options passed to `swww img`, unset ones are left to swww.
+#[derive(Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SwwwTransition {
+    pub transition_type: Option<String>,
+    /// Seconds
+    pub transition_duration: Option<f32>,
+    pub transition_fps: Option<u32>,
+    pub transition_step: Option<u32>,
+    /// Degrees, for the wipe and wave transitions
+    pub transition_angle: Option<f32>,
+    /// Named position such as "top-left", or "x,y"
+    pub transition_pos: Option<String>,
+    pub resize: Option<String>,
+}
+
+impl SwwwTransition {
+    /// `self` with every option set in `other` replaced.
+    pub fn merged(&self, other: &SwwwTransition) -> SwwwTransition {
+        SwwwTransition {
+            transition_type: other
+                .transition_type
+                .clone()
+                .or_else(|| self.transition_type.clone()),
+            transition_duration: other.transition_duration.or(self.transition_duration),
+            transition_fps: other.transition_fps.or(self.transition_fps),
+            transition_step: other.transition_step.or(self.transition_step),
+            transition_angle: other.transition_angle.or(self.transition_angle),
+            transition_pos: other
+                .transition_pos
+                .clone()
+                .or_else(|| self.transition_pos.clone()),
+            resize: other.resize.clone().or_else(|| self.resize.clone()),
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut push = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value);
+            }
+        };
+        push("--transition-type", self.transition_type.clone());
+        push(
+            "--transition-duration",
+            self.transition_duration.map(|v| v.to_string()),
+        );
+        push(
+            "--transition-fps",
+            self.transition_fps.map(|v| v.to_string()),
+        );
+        push(
+            "--transition-step",
+            self.transition_step.map(|v| v.to_string()),
+        );
+        push(
+            "--transition-angle",
+            self.transition_angle.map(|v| v.to_string()),
+        );
+        push("--transition-pos", self.transition_pos.clone());
+        push("--resize", self.resize.clone());
+        args
+    }
+
+    fn validate(&self, section: &str, problems: &mut Vec<String>) {
+        if let Some(kind) = &self.transition_type {
+            problems.extend(validate_transition_type(kind, section).err());
+        }
+        if self.transition_duration.is_some_and(|d| d < 0.0) {
+            problems.push(format!(
+                "{}.transition_duration must not be negative",
+                section
+            ));
+        }
+        if self.transition_fps.is_some_and(|fps| fps == 0 || fps > 255) {
+            problems.push(format!(
+                "{}.transition_fps must be between 1 and 255",
+                section
+            ));
+        }
+        if self
+            .transition_step
+            .is_some_and(|step| step == 0 || step > 255)
+        {
+            problems.push(format!(
+                "{}.transition_step must be between 1 and 255",
+                section
+            ));
+        }
+        if self
+            .transition_angle
+            .is_some_and(|angle| !(0.0..=360.0).contains(&angle))
+        {
+            problems.push(format!(
+                "{}.transition_angle must be between 0 and 360",
+                section
+            ));
+        }
+        if let Some(pos) = &self.transition_pos {
+            let is_point = pos.split_once(',').is_some_and(|(x, y)| {
+                x.trim().parse::<f32>().is_ok() && y.trim().parse::<f32>().is_ok()
+            });
+            if !is_point && !SWWW_POSITIONS.contains(&pos.as_str()) {
+                problems.push(format!(
+                    "Invalid {}.transition_pos '{}'. It must be \"x,y\" or one of {}",
+                    section,
+                    pos,
+                    SWWW_POSITIONS.join(", ")
+                ));
+            }
+        }
+        if let Some(resize) = &self.resize {
+            if !SWWW_RESIZE.contains(&resize.as_str()) {
+                problems.push(format!(
+                    "Invalid {}.resize '{}'. It must be one of {}",
+                    section,
+                    resize,
+                    SWWW_RESIZE.join(", ")
+                ));
+            }
+        }
+    }
+}
+
+pub fn validate_transition_type(kind: &str, section: &str) -> Result<(), String> {
+    if SWWW_TRANSITIONS.contains(&kind) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid {} transition '{}'. It must be one of {}",
+            section,
+            kind,
+            SWWW_TRANSITIONS.join(", ")
+        ))
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Swww {
+    #[serde(flatten)]
+    pub transition: SwwwTransition,
+    /// Pick one of these transition types at random for every change
+    pub random_transitions: Vec<String>,
+    /// Options for single outputs, e.g. [swww.outputs.DP-1]
+    pub outputs: HashMap<String, SwwwTransition>,
+}
+
+impl Swww {
+    fn validate(&self, problems: &mut Vec<String>) {
+        self.transition.validate("swww", problems);
+        for kind in &self.random_transitions {
+            problems.extend(validate_transition_type(kind, "swww.random_transitions").err());
+        }
+        for (output, transition) in &self.outputs {
+            transition.validate(&format!("swww.outputs.{}", output), problems);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FehMode {
+    Scale,
+    Fill,
+    #[default]
+    Max,
+    Center,
+    Tile,
+}
+
+impl FehMode {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            FehMode::Scale => "--bg-scale",
+            FehMode::Fill => "--bg-fill",
+            FehMode::Max => "--bg-max",
+            FehMode::Center => "--bg-center",
+            FehMode::Tile => "--bg-tile",
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct FehOutput {
+    /// Always show this image on the output instead of the refreshed wallpaper
+    pub image: Option<String>,
+}
 
 #[derive(Deserialize)]
+#[serde(default)]
+pub struct Feh {
+    pub mode: FehMode,
+    /// Color around images that do not cover the screen, for max and center
+    pub bg_color: String,
+    /// Let feh write ~/.fehbg
+    pub fehbg: bool,
+    /// Stretch one image across all outputs instead of one per output
+    pub span: bool,
+    /// Settings for single outputs, e.g. [feh.outputs.HDMI-1]. feh uses the same
+    /// mode everywhere, so only the image can differ.
+    pub outputs: HashMap<String, FehOutput>,
+}
+
+impl Default for Feh {
+    fn default() -> Self {
+        Self {
+            mode: FehMode::Max,
+            bg_color: "#000000".to_string(),
+            fehbg: true,
+            span: false,
+            outputs: HashMap::new(),
+        }
+    }
+}
+
+impl Feh {
+    fn validate(&self, problems: &mut Vec<String>) {
+        let color = self.bg_color.trim();
+        let valid_color = match color.strip_prefix('#') {
+            Some(hex) => matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+            // X11 color names such as "black"
+            None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphanumeric()),
+        };
+        if !valid_color {
+            problems.push(format!(
+                "Invalid feh.bg_color '{}'. It must be #rgb, #rrggbb or a color name",
+                self.bg_color
+            ));
+        }
+        if self.span && !self.outputs.is_empty() {
+            problems.push("feh.outputs can not be used together with feh.span".to_string());
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
 pub struct Download {
+    /// Prefer api_key_file or api_key_command to keep the key out of the config
+    #[serde(default)]
     pub api_key: String,
-    pub purity: String,
-    pub categories: String,
+    /// File holding the key, e.g. a secret mounted by the system
+    pub api_key_file: Option<String>,
+    /// Command printing the key, e.g. `pass show wallhaven`
+    pub api_key_command: Option<String>,
+    /// What to download, general.purity when unset
+    pub purity: Option<Purity>,
+    #[serde(default)]
+    pub categories: Categories,
+    /// Keywords to search for
+    #[serde(default)]
     pub query: String,
+    /// Tags every wallpaper must have
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Tags no wallpaper may have
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+    /// Exactly the wallpapers with this Wallhaven tag id, on its own
+    pub tag_id: Option<u64>,
+    /// Only wallpapers from this Wallhaven user
+    pub uploader: Option<String>,
+    /// Only wallpapers similar to this one, an id or link
+    pub similar_to: Option<String>,
+    pub file_type: Option<FileType>,
+    /// Wallhaven API root, defaults to https://wallhaven.cc/api/v1
+    pub base_url: Option<String>,
+}
+
+impl Download {
+    pub fn base_url(&self) -> &str {
+        self.base_url
+            .as_deref()
+            .unwrap_or(crate::wallhaven::DEFAULT_BASE_URL)
+    }
+
+    /// The Wallhaven API key from wherever it is configured, empty without one.
+    ///
+    /// Files and commands are only read here, so commands that never talk to
+    /// Wallhaven work without them.
+    pub fn api_key(&self) -> Result<String, ConfigError> {
+        if let Some(path) = &self.api_key_file {
+            let path = Path::new(path);
+            let key = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                path: path.to_path_buf(),
+                source,
+            })?;
+            return Ok(key.trim().to_string());
+        }
+        if let Some(command) = &self.api_key_command {
+            debug!("Running api_key_command: {}", command);
+            let failed = |reason: String| {
+                ConfigError::Invalid(vec![format!(
+                    "download.api_key_command '{}' failed: {}",
+                    command, reason
+                )])
+            };
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .map_err(|e| failed(e.to_string()))?;
+            if !output.status.success() {
+                return Err(failed(
+                    String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                ));
+            }
+            // Like `pass`, the key is the first line of the output
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            return Ok(stdout.lines().next().unwrap_or_default().trim().to_string());
+        }
+        Ok(self.api_key.clone())
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        let sources = [
+            !self.api_key.is_empty(),
+            self.api_key_file.is_some(),
+            self.api_key_command.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            problems.push(
+                "Only one of download.api_key, api_key_file and api_key_command can be set"
+                    .to_string(),
+            );
+        }
+        if let Some(uploader) = &self.uploader {
+            if uploader.is_empty() || uploader.contains(char::is_whitespace) {
+                problems.push(format!(
+                    "download.uploader '{}' is not a user name",
+                    uploader
+                ));
+            }
+        }
+        if self.tag_id.is_some()
+            && (!self.query.is_empty()
+                || !self.tags.is_empty()
+                || !self.exclude_tags.is_empty()
+                || self.uploader.is_some()
+                || self.similar_to.is_some()
+                || self.file_type.is_some())
+        {
+            problems.push(
+                "download.tag_id can not be combined with query, tags, exclude_tags, uploader, \
+                 similar_to or file_type"
+                    .to_string(),
+            );
+        }
+        if let Some(similar_to) = &self.similar_to {
+            if crate::wallhaven::parse_id(similar_to).is_none() {
+                problems.push(format!(
+                    "download.similar_to '{}' is not a wallpaper id or link",
+                    similar_to
+                ));
+            }
+        }
+        for tag in self.tags.iter().chain(&self.exclude_tags) {
+            // Wallhaven has no syntax for a tag with spaces, it would search for the words
+            if tag.trim().is_empty()
+                || tag.starts_with(['+', '-'])
+                || tag.trim().contains(char::is_whitespace)
+            {
+                problems.push(format!(
+                    "Invalid tag '{}' in download.tags or download.exclude_tags",
+                    tag
+                ));
+            }
+        }
+    }
+
+    /// The search to download from, for wallpapers of `purity`.
+    pub fn search_query(&self, purity: Purity) -> SearchQuery {
+        let mut query = SearchQuery::new()
+            .keywords(&self.query)
+            .categories(self.categories)
+            .purity(purity);
+        for tag in &self.tags {
+            query = query.tag(tag);
+        }
+        for tag in &self.exclude_tags {
+            query = query.exclude_tag(tag);
+        }
+        if let Some(id) = self.tag_id {
+            query = query.tag_id(id);
+        }
+        if let Some(uploader) = &self.uploader {
+            query = query.uploader(uploader);
+        }
+        if let Some(id) = self
+            .similar_to
+            .as_deref()
+            .and_then(crate::wallhaven::parse_id)
+        {
+            query = query.similar_to(&id);
+        }
+        if let Some(file_type) = self.file_type {
+            query = query.file_type(file_type);
+        }
+        query
+    }
+
+    /// Whether a key is configured, without reading files or running commands.
+    fn has_api_key(&self) -> bool {
+        !self.api_key.is_empty() || self.api_key_file.is_some() || self.api_key_command.is_some()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnsplashConfig {
+    pub access_key: String,
+    pub query: Option<String>,
+    /// landscape, portrait or squarish
+    pub orientation: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RedditConfig {
+    pub subreddits: Vec<String>,
+    /// hot, new or top
+    pub sort: Option<String>,
+    /// Time window for `top`: hour, day, week, month, year or all
+    pub time: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BingConfig {
+    pub market: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApodConfig {
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FeedConfig {
+    /// RSS or Atom feeds to take image enclosures and links from
+    pub urls: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FolderConfig {
+    /// Directory to import images from
+    pub path: String,
+    /// Delete the original once it has been copied into wallpaper_dir
+    #[serde(default)]
+    pub remove_after_import: bool,
+}
+
+/// Wallpaper sources besides Wallhaven, each one is enabled by its section.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Sources {
+    pub unsplash: Option<UnsplashConfig>,
+    pub reddit: Option<RedditConfig>,
+    pub bing: Option<BingConfig>,
+    pub apod: Option<ApodConfig>,
+    pub feed: Option<FeedConfig>,
+    pub folder: Option<FolderConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Dedupe {
+    /// Maximum number of differing hash bits for two images to count as the same
+    pub threshold: u32,
+    /// Drop new downloads that are near-duplicates of a wallpaper in the library
+    pub reject_on_download: bool,
+}
+
+impl Default for Dedupe {
+    fn default() -> Self {
+        Self {
+            threshold: 6,
+            reject_on_download: true,
+        }
+    }
+}
+
+/// Limits for one wallpaper directory, unset limits are not enforced.
+#[derive(Deserialize, Default)]
+pub struct Quota {
+    pub max_files: Option<usize>,
+    pub max_size_mb: Option<u64>,
+}
+
+/// Which wallpapers go first when a directory is over its quota.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrunePolicy {
+    Oldest,
+    LeastShown,
+    LowestFavorites,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Prune {
+    pub policy: PrunePolicy,
+    /// Quota of wallpaper_dir itself
+    pub active: Option<Quota>,
+    pub nsfw: Option<Quota>,
+    pub archive: Option<Quota>,
+}
+
+impl Default for Prune {
+    fn default() -> Self {
+        Self {
+            policy: PrunePolicy::Oldest,
+            active: None,
+            nsfw: None,
+            archive: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderMode {
+    /// Scale to cover the output and crop the overflow
+    #[default]
+    Fill,
+    /// Scale to fit, over a blurred copy filling the rest
+    FitBlur,
+    /// Crop around the most detailed region, then scale
+    SmartCrop,
+}
+
+/// Prepare an output-sized copy of the wallpaper in `refresh` instead of handing over the original.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Render {
+    pub enabled: bool,
+    pub mode: RenderMode,
+    /// Output size like "2880x1800", detected from swww or xrandr when unset
+    pub output: Option<String>,
+    /// Where renditions are cached, defaults to ~/.cache/sinh-x/wallpaper
+    pub cache_dir: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TextPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+fn default_text_size() -> f32 {
+    32.0
+}
+
+fn default_text_color() -> String {
+    "#ffffff".to_string()
+}
+
+/// One step of an effects profile, applied in order.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    Blur {
+        sigma: f32,
+    },
+    /// Negative values dim, positive values brighten
+    Brightness {
+        value: i32,
+    },
+    Grayscale,
+    /// Darken the edges, 0.0 does nothing and 1.0 turns the corners black
+    Vignette {
+        strength: f32,
+    },
+    Text {
+        /// Template with {tags}, {time}, {date}, {file} and {quote} placeholders
+        text: String,
+        /// TrueType font, a DejaVu Sans from the usual font directories when unset
+        font: Option<String>,
+        #[serde(default = "default_text_size")]
+        size: f32,
+        #[serde(default)]
+        position: TextPosition,
+        #[serde(default = "default_text_color")]
+        color: String,
+        /// File with one quote per line, for {quote}
+        quotes_file: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Effects {
+    /// Profile applied by `refresh` unless `--profile` is given
+    pub profile: Option<String>,
+    pub profiles: HashMap<String, Vec<Effect>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LockscreenStyle {
+    #[default]
+    Blur,
+    Pixelate,
+    /// Only scaled to the output, e.g. when an effects profile does the rest
+    Plain,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Lockscreen {
+    /// Where the image is written, defaults to lockscreen.png in the cache directory
+    pub path: Option<String>,
+    pub style: LockscreenStyle,
+    pub blur_sigma: f32,
+    /// Edge length of the blocks for the pixelate style
+    pub pixel_size: u32,
+    /// Effects profile drawn on top, e.g. for a clock overlay
+    pub effects: Option<String>,
+    /// Regenerate the lockscreen image after every `refresh`
+    pub on_refresh: bool,
+}
+
+impl Default for Lockscreen {
+    fn default() -> Self {
+        Self {
+            path: None,
+            style: LockscreenStyle::Blur,
+            blur_sigma: 12.0,
+            pixel_size: 16,
+            effects: None,
+            on_refresh: false,
+        }
+    }
+}
+
+/// A point in the day: "HH:MM", or "sunrise"/"sunset" with an optional minute
+/// offset such as "sunset-30".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum TimeSpec {
+    At(NaiveTime),
+    Sunrise(i64),
+    Sunset(i64),
+}
+
+impl TimeSpec {
+    pub fn needs_location(&self) -> bool {
+        !matches!(self, TimeSpec::At(_))
+    }
+}
+
+impl TryFrom<String> for TimeSpec {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        for (name, spec) in [
+            ("sunrise", TimeSpec::Sunrise as fn(i64) -> TimeSpec),
+            ("sunset", TimeSpec::Sunset),
+        ] {
+            if let Some(offset) = value.strip_prefix(name) {
+                let offset: String = offset.split_whitespace().collect();
+                let minutes = if offset.is_empty() {
+                    Ok(0)
+                } else {
+                    offset.parse()
+                };
+                return minutes.map(spec).map_err(|_| {
+                    format!("Invalid offset in '{}', expected e.g. {}-30", value, name)
+                });
+            }
+        }
+        NaiveTime::parse_from_str(value, "%H:%M")
+            .map(TimeSpec::At)
+            .map_err(|_| {
+                format!(
+                    "Invalid time '{}', expected HH:MM, sunrise or sunset",
+                    value
+                )
+            })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tone {
+    Dark,
+    Light,
+}
+
+/// Wallpapers to pick from between `from` and `to`, wrapping around midnight.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleRule {
+    pub name: Option<String>,
+    pub from: TimeSpec,
+    pub to: TimeSpec,
+    /// Sub directory of wallpaper_dir to pick from
+    pub dir: Option<String>,
+    /// Only wallpapers having at least one of these tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub tone: Option<Tone>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Schedule {
+    /// Location for sunrise and sunset, in degrees north and east
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Checked in order, the first rule covering the current time is used
+    pub rules: Vec<ScheduleRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Daemon {
+    /// Minutes between new wallpapers, 0 only keeps dynamic sets on the right frame
+    pub interval: u64,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self { interval: 30 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Logging {
+    /// Terminal level before -v/-q: off, error, warn, info, debug or trace
+    pub level: String,
+    pub format: LogFormat,
+    /// Also log to a file, rotated once it grows past max_size_kb
+    pub file: bool,
+    /// Defaults to wallpaper.log in the data directory
+    pub path: Option<String>,
+    pub file_level: String,
+    pub max_size_kb: u64,
+    /// Rotated files to keep next to the current one
+    pub keep: usize,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            level: "warn".to_string(),
+            format: LogFormat::Text,
+            file: true,
+            path: None,
+            file_level: "info".to_string(),
+            max_size_kb: 1024,
+            keep: 3,
+        }
+    }
+}
+
+impl Logging {
+    fn validate(&self, problems: &mut Vec<String>) {
+        for (key, level) in [("level", &self.level), ("file_level", &self.file_level)] {
+            if level.parse::<log::LevelFilter>().is_err() {
+                problems.push(format!(
+                    "Invalid log.{} '{}'. It must be off, error, warn, info, debug or trace",
+                    key, level
+                ));
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Config {
+    #[serde(default)]
     pub general: General,
     pub swww: Option<Swww>,
     pub feh: Option<Feh>,
+    #[serde(default)]
     pub download: Download,
     pub database: Option<DatabaseConfig>,
+    #[serde(default)]
+    pub sources: Sources,
+    #[serde(default)]
+    pub dedupe: Dedupe,
+    #[serde(default)]
+    pub prune: Prune,
+    #[serde(default)]
+    pub render: Render,
+    #[serde(default)]
+    pub effects: Effects,
+    #[serde(default)]
+    pub lockscreen: Lockscreen,
+    #[serde(default)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub daemon: Daemon,
+    #[serde(default)]
+    pub log: Logging,
 }
 
 impl Config {
-    pub fn new(path: &str) -> Result<Self, toml::de::Error> {
-        let contents = fs::read_to_string(path).expect("Failed to read config file");
-        let mut config: Config = toml::from_str(&contents)?;
+    pub fn new(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let config: Config =
+            toml::from_str(&contents).map_err(|e| ConfigError::parse(path, &contents, &e))?;
+        Ok(config.with_defaults())
+    }
 
-        match &config.database {
+    /// Fill in what can only be decided at runtime.
+    pub(crate) fn with_defaults(mut self) -> Self {
+        match &self.database {
             Some(db_config) => {
                 debug!("Database path: {}", db_config.database_path);
             }
             None => {
-                config.database = Some(DatabaseConfig::default());
+                let default = DatabaseConfig::default();
                 debug!(
                     "Database path is missing. Using default setting: {}",
-                    "~/.local/share/applications/sinh-x/wallpaper/"
+                    default.database_path
                 );
+                self.database = Some(default);
             }
         }
+        self
+    }
 
-        Ok(config)
+    /// What to download, download.purity or else general.purity.
+    pub fn download_purity(&self) -> Purity {
+        self.download.purity.unwrap_or(self.general.purity)
     }
 
-    pub fn validate(&self) -> Result<(), String> {
-        // Add your validation logic here. For example:
-        if self.download.api_key.is_empty() {
-            return Err("API key is missing".to_string());
+    /// Every problem with the config, so they can be fixed in one go.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.download.validate(&mut problems);
+        self.log.validate(&mut problems);
+        if self.download_purity().contains(PurityLevel::Nsfw) && !self.download.has_api_key() {
+            problems.push(
+                "Downloading NSFW wallpapers needs a Wallhaven API key, set download.api_key, \
+                 api_key_file or api_key_command"
+                    .to_string(),
+            );
+        }
+
+        let wallpaper_dir = Path::new(&self.general.wallpaper_dir);
+        if self.general.wallpaper_dir.is_empty() {
+            problems.push("general.wallpaper_dir is missing".to_string());
+        } else if !wallpaper_dir.is_dir() {
+            problems.push(format!(
+                "general.wallpaper_dir '{}' does not exist",
+                self.general.wallpaper_dir
+            ));
         }
 
         match self.general.wallpaper_app.as_str() {
-            "swww" => {
-                if self.swww.is_none() {
-                    return Err("The 'swww' section is missing in the config file".to_string());
+            "swww" => match &self.swww {
+                Some(swww) => swww.validate(&mut problems),
+                None => {
+                    problems.push("The 'swww' section is missing in the config file".to_string())
+                }
+            },
+            "feh" => match &self.feh {
+                Some(feh) => feh.validate(&mut problems),
+                None => {
+                    problems.push("The 'feh' section is missing in the config file".to_string())
+                }
+            },
+            "wayland" => {
+                if !cfg!(feature = "wayland") {
+                    problems.push(
+                        "wallpaper_app 'wayland' needs a build with the 'wayland' feature"
+                            .to_string(),
+                    );
                 }
             }
-            "feh" => {
-                if self.feh.is_none() {
-                    return Err("The 'feh' section is missing in the config file".to_string());
+            "" => problems.push("general.wallpaper_app is missing".to_string()),
+            _ => problems.push(
+                "Invalid wallpaper_app value. It must be 'swww', 'feh' or 'wayland'".to_string(),
+            ),
+        }
+
+        if let Some(output) = &self.render.output {
+            if crate::render::parse_size(output).is_none() {
+                problems.push(format!(
+                    "render.output '{}' is not a size like 2880x1800",
+                    output
+                ));
+            }
+        }
+
+        for (name, effects) in &self.effects.profiles {
+            for effect in effects {
+                if let Effect::Text { color, .. } = effect {
+                    if crate::effects::parse_color(color).is_none() {
+                        problems.push(format!(
+                            "effects.profiles.{}: '{}' is not a color like #ffffff",
+                            name, color
+                        ));
+                    }
                 }
             }
-            _ => return Err("Invalid wallpaper_app value. It must be 'swww' or 'feh'".to_string()),
         }
 
-        Ok(())
+        for profile in [&self.effects.profile, &self.lockscreen.effects]
+            .into_iter()
+            .flatten()
+        {
+            if !self.effects.profiles.contains_key(profile) {
+                problems.push(format!("The effects profile '{}' is not defined", profile));
+            }
+        }
+
+        let schedule = &self.schedule;
+        let uses_sun = schedule
+            .rules
+            .iter()
+            .any(|rule| rule.from.needs_location() || rule.to.needs_location());
+        if uses_sun && (schedule.latitude.is_none() || schedule.longitude.is_none()) {
+            problems.push(
+                "Schedule rules using sunrise or sunset need schedule.latitude and schedule.longitude"
+                    .to_string(),
+            );
+        }
+        if schedule
+            .latitude
+            .is_some_and(|lat| !(-90.0..=90.0).contains(&lat))
+            || schedule
+                .longitude
+                .is_some_and(|lon| !(-180.0..=180.0).contains(&lon))
+        {
+            problems.push("schedule.latitude or schedule.longitude is out of range".to_string());
+        }
+
+        problems
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_config_errors() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+
+        fs::write(
+            &path,
+            "[general]\nwallpaper_dir = \"/tmp\"\nwallpaper_app = 3\n",
+        )
+        .unwrap();
+        match Config::new(&path) {
+            Err(ConfigError::Parse { line, column, .. }) => assert_eq!((line, column), (3, 17)),
+            other => panic!("expected a parse error, got {:?}", other.err()),
+        }
+
+        fs::write(
+            &path,
+            r#"
+[general]
+wallpaper_dir = "/does/not/exist"
+wallpaper_app = "swww"
+
+[download]
+api_key = "5a1d"
+api_key_command = "pass show wallhaven"
+purity = "100"
+categories = "111"
+query = ""
+
+[swww]
+transition_type = "spin"
+"#,
+        )
+        .unwrap();
+        let problems = Config::new(&path).unwrap().problems();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].contains("api_key_command"));
+        assert!(problems[1].contains("/does/not/exist"));
+        assert!(problems[2].contains("spin"));
+
+        // Missing sections and keys are problems too, not parse errors
+        fs::write(&path, "[general]\npurity = \"sfw\"\n").unwrap();
+        let problems = Config::new(&path).unwrap().problems();
+        assert_eq!(
+            problems,
+            [
+                "general.wallpaper_dir is missing",
+                "general.wallpaper_app is missing"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_purity_names_and_bitmasks() {
+        let parse = |value: &str| Purity::try_from(value.to_string());
+        assert_eq!(parse("sfw").unwrap(), Purity::default());
+        assert_eq!(parse("110").unwrap(), parse("SFW, sketchy").unwrap());
+        assert_eq!(parse("sketchy+nsfw").unwrap().bitmask(), "011");
+        assert_eq!(parse("101").unwrap().to_string(), "sfw,nsfw");
+        assert!(parse("000").is_err());
+        assert!(parse("spicy").is_err());
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let config = |download: &str| {
+            fs::write(
+                &path,
+                format!(
+                    "[general]\nwallpaper_dir = {:?}\nwallpaper_app = \"feh\"\npurity = \"sfw\"\n\
+                     [feh]\n[download]\ncategories = \"111\"\nquery = \"\"\n{}",
+                    dir.path(),
+                    download
+                ),
+            )
+            .unwrap();
+            Config::new(&path).unwrap()
+        };
+        let nsfw = config("purity = \"111\"\n");
+        assert!(nsfw.download_purity().contains(PurityLevel::Nsfw));
+        assert_eq!(nsfw.problems().len(), 1);
+        assert!(nsfw.problems()[0].contains("API key"));
+        let keyed = config("purity = \"nsfw\"\napi_key_command = \"pass show wallhaven\"\n");
+        assert_eq!(keyed.problems(), Vec::<String>::new());
+        let spaced = config("tags = [\"digital art\"]\nuploader = \"some one\"\n");
+        assert_eq!(spaced.problems().len(), 2);
+        assert_eq!(config("").download_purity(), Purity::default());
+    }
+
+    #[test]
+    fn test_api_key_sources() {
+        let dir = TempDir::new().unwrap();
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, "from-file\n").unwrap();
+        let download = |file: Option<&Path>, command: Option<&str>| Download {
+            api_key_file: file.map(|path| path.display().to_string()),
+            api_key_command: command.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert_eq!(download(None, None).api_key().unwrap(), "");
+        assert_eq!(
+            download(Some(&key_file), None).api_key().unwrap(),
+            "from-file"
+        );
+        assert_eq!(
+            download(None, Some("printf 'secret\\nlogin: me\\n'"))
+                .api_key()
+                .unwrap(),
+            "secret"
+        );
+        assert!(download(None, Some("exit 3")).api_key().is_err());
     }
 }
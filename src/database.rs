@@ -1,9 +1,9 @@
-use bincode;
 // use colored::*;
 use log::debug;
-use sled::Db;
-use std::path::Path;
+use sled::{Db, Tree};
+use std::path::{Path, PathBuf};
 
+use crate::phash::ImageHash;
 use crate::Wallpaper;
 
 #[derive(Debug)]
@@ -37,6 +37,14 @@ impl From<bincode::Error> for DatabaseError {
 pub struct Database {
     db: Db,
     summary_db: Db,
+    collections: Tree,
+    sources: Tree,
+    hashes: Tree,
+    shown: Tree,
+    removed: Tree,
+    state: Tree,
+    tags: Tree,
+    brightness: Tree,
 }
 
 impl Clone for Database {
@@ -44,6 +52,14 @@ impl Clone for Database {
         Self {
             db: self.db.clone(),
             summary_db: self.summary_db.clone(),
+            collections: self.collections.clone(),
+            sources: self.sources.clone(),
+            hashes: self.hashes.clone(),
+            shown: self.shown.clone(),
+            removed: self.removed.clone(),
+            state: self.state.clone(),
+            tags: self.tags.clone(),
+            brightness: self.brightness.clone(),
         }
     }
 }
@@ -53,7 +69,26 @@ impl Database {
         let _ = std::fs::create_dir_all(path);
         let db = sled::open(path.join("wallpaper_db"))?;
         let summary_db = sled::open(path.join("summary_db"))?;
-        Ok(Self { db, summary_db })
+        let collections = db.open_tree("collections")?;
+        let sources = db.open_tree("sources")?;
+        let hashes = db.open_tree("hashes")?;
+        let shown = db.open_tree("shown")?;
+        let removed = db.open_tree("removed")?;
+        let state = db.open_tree("state")?;
+        let tags = db.open_tree("tags")?;
+        let brightness = db.open_tree("brightness")?;
+        Ok(Self {
+            db,
+            summary_db,
+            collections,
+            sources,
+            hashes,
+            shown,
+            removed,
+            state,
+            tags,
+            brightness,
+        })
     }
 
     pub fn save_to_db(&self, filename: &str, wallpaper: &Wallpaper) -> Result<(), DatabaseError> {
@@ -75,18 +110,148 @@ impl Database {
     }
 
     pub fn get_wallpaper_details(&self, key: &str) -> Result<Wallpaper, DatabaseError> {
-        match self.db.get(key.to_string()) {
+        match self.db.get(key) {
             Ok(Some(value)) => match bincode::deserialize(&value) {
-                Ok(wallpaper) => return Ok(wallpaper),
-                Err(e) => return Err(DatabaseError::BinCodeError(e)),
+                Ok(wallpaper) => Ok(wallpaper),
+                Err(e) => Err(DatabaseError::BinCodeError(e)),
             },
-            Ok(None) => return Err(DatabaseError::KeyNotExist),
+            Ok(None) => Err(DatabaseError::KeyNotExist),
             Err(e) => {
                 debug!("git_wallpaper_details: data handling error!");
-                return Err(DatabaseError::SledError(e));
+                Err(DatabaseError::SledError(e))
             }
         }
     }
+
+    /// Record which source (wallhaven, unsplash, ...) a wallpaper was downloaded from.
+    pub fn save_source(&self, filename: &str, source: &str) -> Result<(), DatabaseError> {
+        self.sources
+            .insert(filename.as_bytes(), source.as_bytes())?;
+        Ok(())
+    }
+
+    /// Source of a wallpaper, records from before sources were tracked are from Wallhaven.
+    pub fn get_source(&self, filename: &str) -> Result<String, DatabaseError> {
+        match self.sources.get(filename)? {
+            Some(value) => Ok(String::from_utf8_lossy(&value).into_owned()),
+            None => match self.db.contains_key(filename)? {
+                true => Ok("wallhaven".to_string()),
+                false => Err(DatabaseError::KeyNotExist),
+            },
+        }
+    }
+
+    pub fn save_hash(&self, filename: &str, hash: &ImageHash) -> Result<(), DatabaseError> {
+        self.hashes
+            .insert(filename.as_bytes(), bincode::serialize(hash)?)?;
+        Ok(())
+    }
+
+    pub fn get_hash(&self, filename: &str) -> Result<ImageHash, DatabaseError> {
+        match self.hashes.get(filename)? {
+            Some(value) => Ok(bincode::deserialize(&value)?),
+            None => Err(DatabaseError::KeyNotExist),
+        }
+    }
+
+    /// All perceptual hashes, keyed by file name.
+    pub fn load_hashes(&self) -> Result<Vec<(String, ImageHash)>, DatabaseError> {
+        let mut hashes = Vec::new();
+        for result in self.hashes.iter() {
+            let (key, value) = result?;
+            hashes.push((
+                String::from_utf8_lossy(&key).into_owned(),
+                bincode::deserialize(&value)?,
+            ));
+        }
+        Ok(hashes)
+    }
+
+    /// Tags are only known for wallpapers fetched through a detail endpoint.
+    pub fn save_tags(&self, filename: &str, tags: &[String]) -> Result<(), DatabaseError> {
+        self.tags
+            .insert(filename.as_bytes(), bincode::serialize(tags)?)?;
+        Ok(())
+    }
+
+    pub fn get_tags(&self, filename: &str) -> Result<Vec<String>, DatabaseError> {
+        match self.tags.get(filename)? {
+            Some(value) => Ok(bincode::deserialize(&value)?),
+            None => Err(DatabaseError::KeyNotExist),
+        }
+    }
+
+    /// Mean luma of an image, 0 for black to 255 for white.
+    pub fn save_brightness(&self, filename: &str, brightness: u8) -> Result<(), DatabaseError> {
+        self.brightness.insert(filename.as_bytes(), &[brightness])?;
+        Ok(())
+    }
+
+    pub fn get_brightness(&self, filename: &str) -> Result<u8, DatabaseError> {
+        match self.brightness.get(filename)? {
+            Some(value) if value.len() == 1 => Ok(value[0]),
+            _ => Err(DatabaseError::KeyNotExist),
+        }
+    }
+
+    /// Remember the wallpaper last set by `refresh`, the original file rather than a rendition.
+    pub fn set_current(&self, path: &Path) -> Result<(), DatabaseError> {
+        self.state
+            .insert("current", path.to_string_lossy().as_bytes())?;
+        Ok(())
+    }
+
+    pub fn get_current(&self) -> Result<PathBuf, DatabaseError> {
+        match self.state.get("current")? {
+            Some(value) => Ok(PathBuf::from(String::from_utf8_lossy(&value).into_owned())),
+            None => Err(DatabaseError::KeyNotExist),
+        }
+    }
+
+    /// Count one more time a wallpaper was set.
+    pub fn record_shown(&self, filename: &str) -> Result<u64, DatabaseError> {
+        let count = self.shown_count(filename)? + 1;
+        self.shown
+            .insert(filename.as_bytes(), &count.to_be_bytes())?;
+        Ok(count)
+    }
+
+    pub fn shown_count(&self, filename: &str) -> Result<u64, DatabaseError> {
+        Ok(self
+            .shown
+            .get(filename)?
+            .and_then(|v| v.as_ref().try_into().ok())
+            .map_or(0, u64::from_be_bytes))
+    }
+
+    /// Note that a wallpaper was deleted from disk, e.g. by pruning.
+    ///
+    /// The wallpaper record itself is kept so it is not downloaded again.
+    pub fn mark_removed(&self, filename: &str) -> Result<(), DatabaseError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.removed
+            .insert(filename.as_bytes(), &now.to_be_bytes())?;
+        self.hashes.remove(filename)?;
+        self.shown.remove(filename)?;
+        self.brightness.remove(filename)?;
+        Ok(())
+    }
+
+    /// Record the file names currently belonging to a Wallhaven collection.
+    pub fn save_collection(&self, key: &str, members: &[String]) -> Result<(), DatabaseError> {
+        self.collections
+            .insert(key.as_bytes(), bincode::serialize(members)?)?;
+        Ok(())
+    }
+
+    pub fn get_collection(&self, key: &str) -> Result<Vec<String>, DatabaseError> {
+        match self.collections.get(key)? {
+            Some(value) => Ok(bincode::deserialize(&value)?),
+            None => Err(DatabaseError::KeyNotExist),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -123,7 +288,7 @@ mod tests {
             "wallhaven-{}-{}.{}",
             wallpaper.id,
             wallpaper.resolution,
-            wallpaper.file_type.split('/').last().unwrap()
+            wallpaper.file_type.split('/').next_back().unwrap()
         );
 
         db.save_to_db(&file_name, &wallpaper).unwrap();
@@ -162,7 +327,7 @@ mod tests {
             "wallhaven-{}-{}.{}",
             wallpaper.id,
             wallpaper.resolution,
-            wallpaper.file_type.split('/').last().unwrap()
+            wallpaper.file_type.split('/').next_back().unwrap()
         );
 
         db.save_to_db(&file_name, &wallpaper).unwrap();
@@ -0,0 +1,263 @@
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use regex::Regex;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::MyError;
+use crate::library;
+
+/// Sub directory of wallpaper_dir holding dynamic sets, one directory or XML file each.
+pub const DIR: &str = "dynamic";
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// A set of images shown one after another over a repeating period.
+///
+/// Either a GNOME background XML, or a directory of images. Images in a directory
+/// whose names start with "HHMM" begin at that time of day, otherwise the images
+/// are spread evenly over the day in name order.
+#[derive(Debug, PartialEq)]
+pub struct DynamicWallpaper {
+    origin: NaiveDateTime,
+    period: u64,
+    /// Seconds after `origin` each image starts, ascending
+    frames: Vec<(u64, PathBuf)>,
+}
+
+/// Whether `path` is a dynamic set: a GNOME XML file, a directory in a `dynamic` directory,
+/// or a directory of images named by their start time. Other directories are not.
+pub fn is_dynamic(path: &Path) -> bool {
+    if path.is_dir() {
+        return path.parent().and_then(Path::file_name) == Some(OsStr::new(DIR))
+            || fs::read_dir(path).into_iter().flatten().any(|entry| {
+                entry.is_ok_and(|entry| {
+                    let image = entry.path();
+                    library::is_image(&image) && start_of_day(&image).is_some()
+                })
+            });
+    }
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("xml"))
+}
+
+/// The dynamic sets below `wallpaper_dir`.
+pub fn sets(wallpaper_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(wallpaper_dir.join(DIR)) else {
+        return Vec::new();
+    };
+    let mut sets: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|path| is_dynamic(path))
+        .collect();
+    sets.sort();
+    sets
+}
+
+/// The image to show for `path` at `now`, which is `path` itself unless it is a dynamic set.
+pub fn resolve(path: &Path, now: NaiveDateTime) -> Result<PathBuf, MyError> {
+    if !is_dynamic(path) {
+        return Ok(path.to_path_buf());
+    }
+    Ok(DynamicWallpaper::load(path)?.frame_at(now).to_path_buf())
+}
+
+fn invalid(path: &Path, reason: &str) -> MyError {
+    MyError::ImageError(format!(
+        "Invalid dynamic wallpaper {}: {}",
+        path.display(),
+        reason
+    ))
+}
+
+impl DynamicWallpaper {
+    pub fn load(path: &Path) -> Result<Self, MyError> {
+        let set = if path.is_dir() {
+            Self::from_dir(path)
+        } else {
+            let base = path.parent().unwrap_or(Path::new("."));
+            Self::from_xml(&fs::read_to_string(path)?, base)
+        };
+        set.ok_or_else(|| invalid(path, "no images found"))
+    }
+
+    fn from_dir(dir: &Path) -> Option<Self> {
+        let mut images: Vec<_> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|path| library::is_image(path))
+            .collect();
+        images.sort();
+        if images.is_empty() {
+            return None;
+        }
+
+        let starts: Option<Vec<u64>> = images.iter().map(|path| start_of_day(path)).collect();
+        let starts = starts.unwrap_or_else(|| {
+            let step = DAY / images.len() as u64;
+            (0..images.len() as u64).map(|i| i * step).collect()
+        });
+        let mut frames: Vec<_> = starts.into_iter().zip(images).collect();
+        frames.sort();
+        Some(Self {
+            origin: NaiveDateTime::default(),
+            period: DAY,
+            frames,
+        })
+    }
+
+    fn from_xml(contents: &str, base: &Path) -> Option<Self> {
+        let element =
+            Regex::new(r"(?s)<(static|transition)\b[^>]*>(.*?)</(?:static|transition)>").unwrap();
+        let size = Regex::new(r"<size[^>]*>([^<]*)</size>").unwrap();
+        let path = |value: &str| {
+            // Multi-resolution entries list one <size> per image, the last is the largest.
+            let value = size
+                .captures_iter(value)
+                .last()
+                .map_or(value, |c| c.get(1).unwrap().as_str());
+            base.join(value.trim())
+        };
+
+        let mut frames = Vec::new();
+        let mut offset = 0.0;
+        for caps in element.captures_iter(contents) {
+            let body = &caps[2];
+            let duration: f64 = tag(body, "duration")?.parse().ok()?;
+            if &caps[1] == "static" {
+                frames.push((offset as u64, path(tag(body, "file")?)));
+            } else {
+                // Switch to the next image half way through, the setter fades between them.
+                frames.push(((offset + duration / 2.0) as u64, path(tag(body, "to")?)));
+            }
+            offset += duration;
+        }
+        if frames.is_empty() || offset < 1.0 {
+            return None;
+        }
+
+        let start = tag(contents, "starttime").unwrap_or_default();
+        let field = |name: &str, default: u32| {
+            tag(start, name)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let origin = NaiveDate::from_ymd_opt(
+            field("year", 2000) as i32,
+            field("month", 1),
+            field("day", 1),
+        )?
+        .and_time(NaiveTime::from_hms_opt(
+            field("hour", 0),
+            field("minute", 0),
+            field("second", 0),
+        )?);
+        Some(Self {
+            origin,
+            period: offset as u64,
+            frames,
+        })
+    }
+
+    /// The image shown at local time `now`.
+    pub fn frame_at(&self, now: NaiveDateTime) -> &Path {
+        let elapsed = (now - self.origin)
+            .num_seconds()
+            .rem_euclid(self.period as i64) as u64;
+        let index = self
+            .frames
+            .iter()
+            .rposition(|(start, _)| *start <= elapsed)
+            // Before the first start time the last image of the previous period is still up.
+            .unwrap_or(self.frames.len() - 1);
+        &self.frames[index].1
+    }
+}
+
+fn tag<'a>(contents: &'a str, name: &str) -> Option<&'a str> {
+    let re = Regex::new(&format!(r"(?s)<{0}>(.*?)</{0}>", name)).unwrap();
+    re.captures(contents)
+        .map(|c| c.get(1).unwrap().as_str().trim())
+}
+
+/// Seconds after midnight from a file name starting with "HHMM".
+fn start_of_day(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    let digits = stem.get(..4)?;
+    let time = NaiveTime::parse_from_str(digits, "%H%M").ok()?;
+    Some((time - NaiveTime::MIN).num_seconds() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn at(date: (i32, u32, u32), hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_gnome_xml_frames() {
+        let xml = r#"<background>
+          <starttime>
+            <year>2011</year><month>11</month><day>24</day>
+            <hour>7</hour><minute>00</minute><second>00</second>
+          </starttime>
+          <static><duration>43200.0</duration><file>day.jpg</file></static>
+          <transition type="overlay">
+            <duration>3600.0</duration><from>day.jpg</from><to>night.jpg</to>
+          </transition>
+          <static>
+            <duration>36000.0</duration>
+            <file><size width="1920" height="1080">night-1080.jpg</size><size width="3840" height="2160">night.jpg</size></file>
+          </static>
+          <transition type="overlay">
+            <duration>3600.0</duration><from>night.jpg</from><to>day.jpg</to>
+          </transition>
+        </background>"#;
+        let set = DynamicWallpaper::from_xml(xml, Path::new("/sets/gnome")).unwrap();
+        let day = Path::new("/sets/gnome/day.jpg");
+        let night = Path::new("/sets/gnome/night.jpg");
+
+        assert_eq!(set.frame_at(at((2024, 3, 1), 12, 0)), day);
+        // The transition starts at 19:00 and switches at 19:30.
+        assert_eq!(set.frame_at(at((2024, 3, 1), 19, 15)), day);
+        assert_eq!(set.frame_at(at((2024, 3, 1), 19, 45)), night);
+        assert_eq!(set.frame_at(at((2024, 3, 2), 3, 0)), night);
+        assert_eq!(set.frame_at(at((2024, 3, 2), 6, 45)), day);
+    }
+
+    #[test]
+    fn test_directory_frames() {
+        let dir = TempDir::new().unwrap();
+        for name in [
+            "0600-dawn.jpg",
+            "1200-noon.jpg",
+            "2000-dusk.jpg",
+            "notes.txt",
+        ] {
+            fs::write(dir.path().join(name), b"image").unwrap();
+        }
+        assert!(is_dynamic(dir.path()));
+        let pictures = TempDir::new().unwrap();
+        fs::write(pictures.path().join("holiday.jpg"), b"image").unwrap();
+        assert!(!is_dynamic(pictures.path()));
+        let set = DynamicWallpaper::load(dir.path()).unwrap();
+        let frame = |h, m| {
+            set.frame_at(at((2024, 6, 1), h, m))
+                .file_name()
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(frame(6, 0), "0600-dawn.jpg");
+        assert_eq!(frame(13, 30), "1200-noon.jpg");
+        assert_eq!(frame(2, 0), "2000-dusk.jpg");
+    }
+}
@@ -0,0 +1,327 @@
+use ab_glyph::{FontVec, PxScale};
+use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::drawing::{draw_text_mut, text_size};
+use log::debug;
+use rand::seq::SliceRandom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Effect, TextPosition};
+use crate::error::MyError;
+use crate::render;
+
+const FONT_CANDIDATES: [&str; 4] = [
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/run/current-system/sw/share/X11/fonts/DejaVuSans.ttf",
+];
+
+/// What text overlays can refer to.
+pub struct TextContext {
+    pub tags: Vec<String>,
+    pub file: String,
+}
+
+/// Parse a `#rrggbb` color.
+pub(crate) fn parse_color(color: &str) -> Option<Rgb<u8>> {
+    let hex = color.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+fn random_quote(quotes_file: Option<&str>) -> Result<String, MyError> {
+    let Some(quotes_file) = quotes_file else {
+        return Ok(String::new());
+    };
+    let quotes = fs::read_to_string(quotes_file)?;
+    let lines: Vec<_> = quotes.lines().filter(|l| !l.trim().is_empty()).collect();
+    Ok(lines
+        .choose(&mut rand::thread_rng())
+        .map_or_else(String::new, |l| l.trim().to_string()))
+}
+
+/// Fill in the placeholders of text overlays.
+fn resolve(effects: &[Effect], context: &TextContext) -> Result<Vec<Effect>, MyError> {
+    let now = chrono::Local::now();
+    effects
+        .iter()
+        .map(|effect| match effect {
+            Effect::Text {
+                text,
+                font,
+                size,
+                position,
+                color,
+                quotes_file,
+            } => {
+                let mut resolved = text
+                    .replace("{tags}", &context.tags.join(", "))
+                    .replace("{time}", &now.format("%H:%M").to_string())
+                    .replace("{date}", &now.format("%Y-%m-%d").to_string())
+                    .replace("{file}", &context.file);
+                if resolved.contains("{quote}") {
+                    resolved = resolved.replace("{quote}", &random_quote(quotes_file.as_deref())?);
+                }
+                Ok(Effect::Text {
+                    text: resolved,
+                    font: font.clone(),
+                    size: *size,
+                    position: *position,
+                    color: color.clone(),
+                    quotes_file: quotes_file.clone(),
+                })
+            }
+            other => Ok(other.clone()),
+        })
+        .collect()
+}
+
+/// Apply `effects` to `source`, caching the result next to the renditions.
+///
+/// Only the latest result is kept for a source and profile, as text with `{time}`,
+/// `{date}` or `{quote}` comes out different on every refresh.
+pub fn apply(
+    source: &Path,
+    effects: &[Effect],
+    context: &TextContext,
+    cache_dir: &Path,
+) -> Result<PathBuf, MyError> {
+    let profile_key = render::source_key(source, &format!("{:?}", effects))?;
+    let effects = resolve(effects, context)?;
+    let text_key = render::source_key(source, &format!("{:?}", effects))?;
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}-{:08x}-", stem, profile_key);
+    let dir = cache_dir.join("effects");
+    let target = dir.join(format!("{}{:08x}.jpg", prefix, text_key));
+    if target.exists() {
+        debug!("Using cached effects {:?}", target);
+        render::touch(&target);
+        return Ok(target);
+    }
+
+    let image = process(image::open(source)?, &effects)?;
+
+    fs::create_dir_all(&dir)?;
+    render::save_jpeg(&image, &target)?;
+    debug!("Applied {} effects to {:?}", effects.len(), source);
+    for entry in fs::read_dir(&dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        if path != target && entry.file_name().to_string_lossy().starts_with(&prefix) {
+            let _ = fs::remove_file(path);
+        }
+    }
+    render::trim_cache(&dir, render::CACHED_FILES);
+    Ok(target)
+}
+
+/// Apply resolved `effects` to an image in memory.
+fn process(mut image: DynamicImage, effects: &[Effect]) -> Result<DynamicImage, MyError> {
+    for effect in effects {
+        image = apply_effect(image, effect)?;
+    }
+    Ok(image)
+}
+
+/// Like `apply`, for images that are not written to the cache.
+pub fn apply_to_image(
+    image: DynamicImage,
+    effects: &[Effect],
+    context: &TextContext,
+) -> Result<DynamicImage, MyError> {
+    process(image, &resolve(effects, context)?)
+}
+
+fn apply_effect(image: DynamicImage, effect: &Effect) -> Result<DynamicImage, MyError> {
+    Ok(match effect {
+        Effect::Blur { sigma } => image.fast_blur(*sigma),
+        Effect::Brightness { value } => image.brighten(*value),
+        Effect::Grayscale => DynamicImage::ImageRgb8(image.grayscale().to_rgb8()),
+        Effect::Vignette { strength } => {
+            DynamicImage::ImageRgb8(vignette(image.to_rgb8(), *strength))
+        }
+        Effect::Text {
+            text,
+            font,
+            size,
+            position,
+            color,
+            ..
+        } => {
+            let mut canvas = image.to_rgb8();
+            let color = parse_color(color)
+                .ok_or_else(|| MyError::ImageError(format!("Invalid text color '{}'", color)))?;
+            draw_text(&mut canvas, text, font.as_deref(), *size, *position, color)?;
+            DynamicImage::ImageRgb8(canvas)
+        }
+    })
+}
+
+fn vignette(mut image: RgbImage, strength: f32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let max_distance = (cx * cx + cy * cy).sqrt();
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let distance = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt() / max_distance;
+        let factor = (1.0 - strength * distance * distance).clamp(0.0, 1.0);
+        for channel in pixel.0.iter_mut() {
+            *channel = (*channel as f32 * factor) as u8;
+        }
+    }
+    image
+}
+
+fn load_font(font: Option<&str>) -> Result<FontVec, MyError> {
+    let path = match font {
+        Some(font) => PathBuf::from(font),
+        None => FONT_CANDIDATES
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| p.exists())
+            .ok_or_else(|| {
+                MyError::ImageError("No font found, set `font` for the text effect".to_string())
+            })?,
+    };
+    FontVec::try_from_vec(fs::read(&path)?)
+        .map_err(|e| MyError::ImageError(format!("Invalid font {}: {}", path.display(), e)))
+}
+
+fn draw_text(
+    canvas: &mut RgbImage,
+    text: &str,
+    font: Option<&str>,
+    size: f32,
+    position: TextPosition,
+    color: Rgb<u8>,
+) -> Result<(), MyError> {
+    if text.trim().is_empty() {
+        return Ok(());
+    }
+    let font = load_font(font)?;
+    let scale = PxScale::from(size);
+    let (text_width, text_height) = text_size(scale, &font, text);
+    let margin = (size as i32).max(16);
+    let (width, height) = (canvas.width() as i32, canvas.height() as i32);
+    let (text_width, text_height) = (text_width as i32, text_height as i32);
+    let (x, y) = match position {
+        TextPosition::TopLeft => (margin, margin),
+        TextPosition::TopRight => (width - text_width - margin, margin),
+        TextPosition::BottomLeft => (margin, height - text_height - margin),
+        TextPosition::BottomRight => (width - text_width - margin, height - text_height - margin),
+        TextPosition::Center => ((width - text_width) / 2, (height - text_height) / 2),
+    };
+
+    // A soft shadow keeps the text readable on light wallpapers.
+    let offset = (size / 16.0).max(1.0) as i32;
+    draw_text_mut(
+        canvas,
+        Rgb([0, 0, 0]),
+        x + offset,
+        y + offset,
+        scale,
+        &font,
+        text,
+    );
+    draw_text_mut(canvas, color, x, y, scale, &font, text);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn context() -> TextContext {
+        TextContext {
+            tags: vec!["anime".to_string(), "sky".to_string()],
+            file: "wallhaven-d6jzel-4999x3541.png".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_effects_are_applied_in_order_and_cached() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("flat.png");
+        RgbImage::from_pixel(200, 100, Rgb([200, 100, 50]))
+            .save(&source)
+            .unwrap();
+        let effects = [
+            Effect::Grayscale,
+            Effect::Brightness { value: -40 },
+            Effect::Vignette { strength: 1.0 },
+        ];
+
+        let output = apply(&source, &effects, &context(), dir.path()).unwrap();
+        let image = image::open(&output).unwrap().to_rgb8();
+        let center = image.get_pixel(100, 50);
+        assert!(center[0].abs_diff(center[2]) <= 2, "{:?}", center);
+        assert!((center[0] as i32 - 78).abs() <= 4, "{:?}", center);
+        assert!(image.get_pixel(0, 0)[0] < 8);
+
+        assert_eq!(
+            apply(&source, &effects, &context(), dir.path()).unwrap(),
+            output
+        );
+    }
+
+    #[test]
+    fn test_changing_text_replaces_the_cached_file() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("flat.png");
+        RgbImage::from_pixel(200, 100, Rgb([200, 100, 50]))
+            .save(&source)
+            .unwrap();
+        // Blank texts are not drawn, so no font is needed
+        let effects = [Effect::Text {
+            text: "{file}".to_string(),
+            font: None,
+            size: 32.0,
+            position: TextPosition::BottomRight,
+            color: "#ffffff".to_string(),
+            quotes_file: None,
+        }];
+
+        let mut outputs = Vec::new();
+        for file in [" ", "  "] {
+            let context = TextContext {
+                tags: Vec::new(),
+                file: file.to_string(),
+            };
+            outputs.push(apply(&source, &effects, &context, dir.path()).unwrap());
+        }
+        assert_ne!(outputs[0], outputs[1]);
+        let cached: Vec<_> = fs::read_dir(dir.path().join("effects"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(cached, [outputs[1].clone()]);
+    }
+
+    #[test]
+    fn test_text_placeholders() {
+        let effects = resolve(
+            &[Effect::Text {
+                text: "{tags} | {file}".to_string(),
+                font: None,
+                size: 32.0,
+                position: TextPosition::BottomRight,
+                color: "#ffffff".to_string(),
+                quotes_file: None,
+            }],
+            &context(),
+        )
+        .unwrap();
+        match &effects[0] {
+            Effect::Text { text, .. } => {
+                assert_eq!(text, "anime, sky | wallhaven-d6jzel-4999x3541.png")
+            }
+            other => panic!("Unexpected effect {:?}", other),
+        }
+        assert_eq!(parse_color("#ff8000"), Some(Rgb([255, 128, 0])));
+        assert_eq!(parse_color("white"), None);
+        assert_eq!(parse_color("#ééé"), None);
+    }
+}
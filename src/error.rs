@@ -1,3 +1,4 @@
+use crate::config::ConfigError;
 use crate::database::DatabaseError;
 use serde_json::Error as SerdeJsonError;
 use std::convert::From;
@@ -10,6 +11,9 @@ pub enum MyError {
     Reqwest(reqwest::Error),
     JsonError(String),
     DatabaseError(String),
+    SourceError(String),
+    ImageError(String),
+    ConfigError(ConfigError),
 }
 
 impl fmt::Display for MyError {
@@ -19,6 +23,9 @@ impl fmt::Display for MyError {
             MyError::Io(err) => write!(f, "IO error: {}", err),
             MyError::Reqwest(err) => write!(f, "Reqwest error: {}", err),
             MyError::JsonError(err) => write!(f, "JSON error: {}", err),
+            MyError::SourceError(err) => write!(f, "Source error: {}", err),
+            MyError::ImageError(err) => write!(f, "Image error: {}", err),
+            MyError::ConfigError(err) => write!(f, "Config error: {}", err),
         }
     }
 }
@@ -48,3 +55,15 @@ impl From<DatabaseError> for MyError {
         MyError::DatabaseError(err.to_string())
     }
 }
+
+impl From<image::ImageError> for MyError {
+    fn from(err: image::ImageError) -> MyError {
+        MyError::ImageError(err.to_string())
+    }
+}
+
+impl From<ConfigError> for MyError {
+    fn from(err: ConfigError) -> MyError {
+        MyError::ConfigError(err)
+    }
+}
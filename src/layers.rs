@@ -0,0 +1,438 @@
+use log::debug;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde_path_to_error::Segment;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+use toml_edit::ImDocument;
+
+use crate::config::{Config, ConfigError};
+
+/// Values every config starts from, so a layer only has to hold real choices.
+const DEFAULTS: &str = r#"
+[general]
+purity = "sfw"
+
+[download]
+api_key = ""
+categories = "111"
+query = ""
+"#;
+
+/// Environment variables `SINH_WALLPAPER_<SECTION>__<KEY>` set `section.key`.
+const ENV_PREFIX: &str = "SINH_WALLPAPER_";
+
+/// Keys whose values are not printed by `config show`.
+const SECRETS: [&str; 2] = ["api_key", "access_key"];
+
+/// The config merged from every layer, remembering which layer set each value.
+///
+/// Layers are applied in order, later ones win: the built-in defaults, the system
+/// configs from `$XDG_CONFIG_DIRS`, the user config, `config.<hostname>.toml` next to
+/// it, `SINH_WALLPAPER_*` environment variables and finally `--set` flags.
+pub struct Layers {
+    table: Table,
+    layers: Vec<Layer>,
+    /// Index into `layers` of the layer that set each value, by dotted key
+    origins: BTreeMap<String, usize>,
+}
+
+struct Layer {
+    name: String,
+    /// Path and contents of a file layer, to point at the line a value came from
+    file: Option<(PathBuf, String)>,
+}
+
+/// Resolve the config for `user_config`, which must exist when it was asked for by
+/// name or there is no system config to fall back to.
+pub fn load(
+    user_config: &Path,
+    explicit: bool,
+    overrides: &[String],
+) -> Result<Layers, ConfigError> {
+    let mut layers = Layers::new();
+    for path in system_configs() {
+        layers.add_file(&path, "system")?;
+    }
+    if explicit || layers.files().is_empty() || user_config.exists() {
+        layers.add_file(user_config, "user")?;
+    }
+    if let Some(path) = host_config(user_config) {
+        layers.add_file(&path, "host")?;
+    }
+    layers.add_env(env::vars())?;
+    for set in overrides {
+        let (key, value) = set.split_once('=').ok_or_else(|| {
+            ConfigError::Invalid(vec![format!("--set expects key=value, got '{}'", set)])
+        })?;
+        layers.set(key.trim(), value.trim(), "cli (--set)")?;
+    }
+    Ok(layers)
+}
+
+/// `sinh-x/wallpaper/config.toml` in each of `$XDG_CONFIG_DIRS`, least important first.
+fn system_configs() -> Vec<PathBuf> {
+    let dirs = env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".to_string());
+    dirs.split(':')
+        .rev()
+        .map(|dir| Path::new(dir).join("sinh-x/wallpaper/config.toml"))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// `config.<hostname>.toml` next to the user config, if there is one.
+fn host_config(user_config: &Path) -> Option<PathBuf> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())?;
+    let hostname = hostname.trim();
+    if hostname.is_empty() {
+        return None;
+    }
+    let path = user_config.with_file_name(format!("config.{}.toml", hostname));
+    path.is_file().then_some(path)
+}
+
+/// Call `on_change` whenever one of the config `files` or a host config next to them
+/// is written, for as long as the returned watcher lives.
+///
+/// The directories are watched rather than the files, as editors often save by
+/// replacing the file.
+pub fn watch(
+    files: &[PathBuf],
+    on_change: impl Fn() + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    let files: Vec<_> = files
+        .iter()
+        .map(|file| fs::canonicalize(file).unwrap_or_else(|_| file.clone()))
+        .collect();
+    let mut dirs: Vec<_> = files
+        .iter()
+        .filter_map(|file| file.parent().map(Path::to_path_buf))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let is_config = {
+        let files = files.clone();
+        move |path: &Path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            files.iter().any(|file| file == path)
+                || (name.starts_with("config.") && name.ends_with(".toml"))
+        }
+    };
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() && event.paths.iter().any(|path| is_config(path)) {
+                debug!("Config changed: {:?}", event);
+                on_change();
+            }
+        }
+    })?;
+    for dir in dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        let mut layers = Self {
+            table: Table::new(),
+            layers: Vec::new(),
+            origins: BTreeMap::new(),
+        };
+        let defaults: Table = toml::from_str(DEFAULTS).expect("the defaults are valid TOML");
+        layers.merge(defaults, "default".to_string(), None);
+        layers
+    }
+
+    /// The config files that were read, in the order they were applied.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.layers
+            .iter()
+            .filter_map(|layer| Some(layer.file.as_ref()?.0.clone()))
+            .collect()
+    }
+
+    fn merge(&mut self, table: Table, name: String, file: Option<(PathBuf, String)>) {
+        let index = self.layers.len();
+        self.layers.push(Layer { name, file });
+        merge_into(&mut self.table, table, "", index, &mut self.origins);
+    }
+
+    pub fn add_file(&mut self, path: &Path, layer: &str) -> Result<(), ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let table: Table =
+            toml::from_str(&contents).map_err(|e| ConfigError::parse(path, &contents, &e))?;
+        self.merge(
+            table,
+            format!("{} ({})", layer, path.display()),
+            Some((path.to_path_buf(), contents)),
+        );
+        Ok(())
+    }
+
+    /// Apply the `SINH_WALLPAPER_<SECTION>__<KEY>` variables among `vars`.
+    pub fn add_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        let mut vars: Vec<_> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.contains("__"))
+            .collect();
+        // Apply them in a fixed order, so a section always loses to its own keys
+        vars.sort();
+        for (name, raw) in vars {
+            let key = name[ENV_PREFIX.len()..]
+                .to_ascii_lowercase()
+                .replace("__", ".");
+            self.set(&key, &raw, &format!("env ({})", name))?;
+        }
+        Ok(())
+    }
+
+    /// Set the dotted `key` from text, which is taken as a string where the key
+    /// already holds one and parsed as a TOML value otherwise.
+    pub fn set(&mut self, key: &str, raw: &str, layer: &str) -> Result<(), ConfigError> {
+        let parts: Vec<_> = key.split('.').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(ConfigError::Invalid(vec![format!(
+                "Invalid key '{}' from {}",
+                key, layer
+            )]));
+        }
+        let value = match self.get(&parts) {
+            Some(Value::String(_)) => Value::String(raw.to_string()),
+            _ => toml::from_str::<Table>(&format!("value = {}", raw))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or_else(|| Value::String(raw.to_string())),
+        };
+        let table = parts.iter().rev().fold(value, |value, part| {
+            Value::Table(Table::from_iter([(part.to_string(), value)]))
+        });
+        if let Value::Table(table) = table {
+            self.merge(table, layer.to_string(), None);
+        }
+        Ok(())
+    }
+
+    fn get(&self, parts: &[&str]) -> Option<&Value> {
+        let (last, sections) = parts.split_last()?;
+        let mut table = &self.table;
+        for part in sections {
+            table = table.get(*part)?.as_table()?;
+        }
+        table.get(*last)
+    }
+
+    /// The merged config, pointing at the file and line of a value that does not fit
+    /// where it came from a file.
+    pub fn config(&self) -> Result<Config, ConfigError> {
+        let config: Config = serde_path_to_error::deserialize(Value::Table(self.table.clone()))
+            .map_err(|e| self.locate(e))?;
+        Ok(config.with_defaults())
+    }
+
+    fn locate(&self, err: serde_path_to_error::Error<toml::de::Error>) -> ConfigError {
+        let message = err.inner().message().trim().to_string();
+        let segments: Vec<_> = err.path().iter().collect();
+        let keys: Vec<_> = segments
+            .iter()
+            .map_while(|segment| match segment {
+                Segment::Map { key } => Some(key.as_str()),
+                _ => None,
+            })
+            .collect();
+        // The value itself, or the array it is part of
+        let origin = (1..=keys.len())
+            .rev()
+            .find_map(|len| self.origins.get(&keys[..len].join(".")));
+        let file = origin.and_then(|&index| self.layers[index].file.as_ref());
+        if let Some((path, contents)) = file {
+            if let Some(offset) = offset_of(contents, &segments) {
+                return ConfigError::at(path, contents, offset, message);
+            }
+        }
+        if keys.is_empty() {
+            ConfigError::Invalid(vec![message])
+        } else {
+            ConfigError::Invalid(vec![format!("{}: {}", err.path(), message)])
+        }
+    }
+
+    /// Every value as `(key, value, layer)`, sorted by key, with secrets hidden.
+    pub fn resolved(&self) -> Vec<(String, String, String)> {
+        let mut values = Vec::new();
+        leaves(&self.table, "", &mut values);
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let shown = match &value {
+                    Value::String(s)
+                        if !s.is_empty() && SECRETS.iter().any(|k| key.ends_with(k)) =>
+                    {
+                        "\"****\"".to_string()
+                    }
+                    value => value.to_string(),
+                };
+                let layer = self
+                    .origins
+                    .get(&key)
+                    .map_or("default", |&index| &self.layers[index].name)
+                    .to_string();
+                (key, shown, layer)
+            })
+            .collect()
+    }
+}
+
+/// Offset in `contents` of the value at `path`, or of the closest table holding it.
+fn offset_of(contents: &str, path: &[&Segment]) -> Option<usize> {
+    let document = ImDocument::parse(contents).ok()?;
+    let mut item = document.as_item();
+    let mut offset = None;
+    for segment in path {
+        let next = match segment {
+            Segment::Map { key } => item.get(key.as_str()),
+            Segment::Seq { index } => item.get(*index),
+            _ => None,
+        };
+        match next {
+            Some(next) => item = next,
+            None => break,
+        }
+        offset = item.span().map(|span| span.start).or(offset);
+    }
+    offset
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Merge `source` into `target` table by table, recording `layer` for every value
+/// it sets. Arrays are values too, a later layer replaces them as a whole.
+fn merge_into(
+    target: &mut Table,
+    source: Table,
+    prefix: &str,
+    layer: usize,
+    origins: &mut BTreeMap<String, usize>,
+) {
+    for (key, value) in source {
+        let path = join(prefix, &key);
+        match (target.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(table)) => {
+                merge_into(existing, table, &path, layer, origins)
+            }
+            (_, Value::Table(table)) => {
+                forget(origins, &path);
+                let mut fresh = Table::new();
+                merge_into(&mut fresh, table, &path, layer, origins);
+                target.insert(key, Value::Table(fresh));
+            }
+            (_, value) => {
+                forget(origins, &path);
+                origins.insert(path, layer);
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Drop the origins of `path` and everything below it.
+fn forget(origins: &mut BTreeMap<String, usize>, path: &str) {
+    let below = format!("{}.", path);
+    origins.retain(|key, _| key != path && !key.starts_with(&below));
+}
+
+fn leaves(table: &Table, prefix: &str, values: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        let path = join(prefix, key);
+        match value {
+            Value::Table(table) => leaves(table, &path, values),
+            value => values.push((path, value.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let dir = TempDir::new().unwrap();
+        let system = dir.path().join("system.toml");
+        fs::write(
+            &system,
+            "[general]\nwallpaper_dir = \"/shared\"\nwallpaper_app = \"feh\"\n[feh]\n[daemon]\ninterval = 10\n",
+        )
+        .unwrap();
+        let user = dir.path().join("config.toml");
+        fs::write(&user, "[general]\nwallpaper_app = \"swww\"\n[swww]\n").unwrap();
+
+        let mut layers = Layers::new();
+        layers.add_file(&system, "system").unwrap();
+        layers.add_file(&user, "user").unwrap();
+        layers
+            .add_env([
+                (
+                    "SINH_WALLPAPER_DOWNLOAD__API_KEY".to_string(),
+                    "12345".to_string(),
+                ),
+                ("SINH_WALLPAPER_CONFIG".to_string(), "/ignored".to_string()),
+            ])
+            .unwrap();
+        layers.set("daemon.interval", "5", "cli (--set)").unwrap();
+
+        let config = layers.config().unwrap();
+        assert_eq!(config.general.wallpaper_dir, "/shared");
+        assert_eq!(config.general.wallpaper_app, "swww");
+        // A key holding a string stays one, even if it looks like a number
+        assert_eq!(config.download.api_key, "12345");
+        assert_eq!(config.download.categories, Default::default());
+        assert_eq!(config.daemon.interval, 5);
+
+        let resolved = layers.resolved();
+        let layer_of = |key: &str| {
+            let (_, value, layer) = resolved.iter().find(|(k, _, _)| k == key).unwrap();
+            (value.as_str(), layer.split(' ').next().unwrap())
+        };
+        assert_eq!(layer_of("general.wallpaper_dir"), ("\"/shared\"", "system"));
+        assert_eq!(layer_of("general.wallpaper_app"), ("\"swww\"", "user"));
+        assert_eq!(layer_of("download.api_key"), ("\"****\"", "env"));
+        assert_eq!(layer_of("download.query"), ("\"\"", "default"));
+        assert_eq!(layer_of("daemon.interval"), ("5", "cli"));
+
+        // A wrong value is reported where it was written
+        fs::write(&user, "[general]\nwallpaper_app = 3\n").unwrap();
+        let mut layers = Layers::new();
+        layers.add_file(&system, "system").unwrap();
+        layers.add_file(&user, "user").unwrap();
+        match layers.config() {
+            Err(ConfigError::Parse { path, line, .. }) => {
+                assert_eq!(path, user);
+                assert_eq!(line, 2);
+            }
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
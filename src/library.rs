@@ -0,0 +1,365 @@
+use log::debug;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::{Prune, PrunePolicy, PurityLevel, Quota};
+use crate::database::Database;
+use crate::dynamic;
+use crate::error::MyError;
+use crate::output;
+use crate::phash::{self, ImageHash};
+
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// All images below `dir`, except the collection mirrors and dynamic sets which have to
+/// stay complete.
+pub fn image_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                if current != dir
+                    || (entry.file_name() != "collections" && entry.file_name() != dynamic::DIR)
+                {
+                    pending.push(path);
+                }
+            } else if is_image(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The images `refresh` picks from: the ones directly in `dir` and in the sub
+/// directories downloads are routed into by purity.
+pub fn wallpapers(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.to_path_buf()];
+    for level in PurityLevel::ALL {
+        if let Some(sub) = level.dir() {
+            if !dirs.contains(&dir.join(sub)) {
+                dirs.push(dir.join(sub));
+            }
+        }
+    }
+    let mut files: Vec<_> = dirs
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok).map(|e| e.path()))
+        .filter(|path| path.is_file() && is_image(path))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Purity of a library file from its metadata, or from the directory it is in when
+/// there is none, e.g. for files added by hand.
+pub fn purity_of(db: &Database, path: &Path) -> PurityLevel {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Ok(wallpaper) = db.get_wallpaper_details(&file_name) {
+        if let Some(level) = PurityLevel::from_name(&wallpaper.purity) {
+            return level;
+        }
+    }
+    let dir = path.parent().and_then(Path::file_name);
+    if dir.is_some() && dir == PurityLevel::Nsfw.dir().map(OsStr::new) {
+        PurityLevel::Nsfw
+    } else {
+        PurityLevel::Sfw
+    }
+}
+
+/// Perceptual hash of a library file, computed and stored on first use.
+pub fn hash_of(db: &Database, path: &Path) -> Result<ImageHash, MyError> {
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    if let Ok(hash) = db.get_hash(&file_name) {
+        return Ok(hash);
+    }
+    let hash = ImageHash::from_file(path)?;
+    db.save_hash(&file_name, &hash)?;
+    Ok(hash)
+}
+
+/// Mean luma of a library file, computed and stored on first use.
+pub fn brightness_of(db: &Database, path: &Path) -> Result<u8, MyError> {
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    if let Ok(brightness) = db.get_brightness(&file_name) {
+        return Ok(brightness);
+    }
+    let image = image::open(path)?.thumbnail(64, 64).to_luma8();
+    let total: u64 = image.pixels().map(|p| p.0[0] as u64).sum();
+    let brightness = (total / image.pixels().len().max(1) as u64) as u8;
+    db.save_brightness(&file_name, brightness)?;
+    Ok(brightness)
+}
+
+/// Group near-duplicate images below `dir` and remove all but the highest resolution of each.
+///
+/// Returns the groups, the kept image first.
+pub fn dedupe(
+    dir: &Path,
+    db: &Database,
+    threshold: u32,
+    dry_run: bool,
+) -> Result<Vec<Vec<PathBuf>>, MyError> {
+    let files = image_files(dir);
+
+    let pb = output::progress_bar(files.len() as u64);
+    let mut entries = Vec::new();
+    for path in files {
+        match hash_of(db, &path) {
+            Ok(hash) => entries.push((path, hash)),
+            Err(e) => debug!("Could not hash {:?}: {}", path, e),
+        }
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+
+    let hashes: Vec<_> = entries
+        .iter()
+        .map(|(path, hash)| (path.clone(), *hash))
+        .collect();
+    let groups = phash::group_duplicates(&hashes, threshold);
+    let size_of = |path: &PathBuf| {
+        let hash = &entries.iter().find(|(p, _)| p == path).unwrap().1;
+        format!("{}x{}", hash.width, hash.height)
+    };
+
+    let mut removed = 0;
+    for group in &groups {
+        let (keep, duplicates) = group.split_first().unwrap();
+        say!("Keeping {} ({})", keep.display(), size_of(keep));
+        for duplicate in duplicates {
+            say!(
+                "  Removing {} ({})",
+                duplicate.display(),
+                size_of(duplicate)
+            );
+            if !dry_run {
+                fs::remove_file(duplicate)?;
+                db.mark_removed(&duplicate.file_name().unwrap().to_string_lossy())?;
+            }
+            removed += 1;
+        }
+    }
+
+    say!(
+        "{} groups of near-duplicates, {} {}",
+        groups.len(),
+        removed,
+        if dry_run {
+            "files would be removed"
+        } else {
+            "files removed"
+        }
+    );
+    Ok(groups)
+}
+
+struct Candidate {
+    path: PathBuf,
+    file_name: String,
+    size: u64,
+    modified: SystemTime,
+    shown: u64,
+    favorites: u32,
+}
+
+/// Delete wallpapers from `dir` until it fits `quota`, in the order given by `policy`.
+///
+/// Returns the files and bytes removed (or that would be, with `dry_run`).
+fn prune_dir(
+    dir: &Path,
+    quota: &Quota,
+    policy: PrunePolicy,
+    protected: &HashSet<String>,
+    db: &Database,
+    dry_run: bool,
+) -> Result<(Vec<PathBuf>, u64), MyError> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok((Vec::new(), 0));
+    };
+    let mut candidates = Vec::new();
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || !is_image(&path) {
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        candidates.push(Candidate {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            shown: db.shown_count(&file_name)?,
+            favorites: db
+                .get_wallpaper_details(&file_name)
+                .map_or(0, |w| w.favorites),
+            path,
+            file_name,
+        });
+    }
+
+    let mut files = candidates.len();
+    let mut size: u64 = candidates.iter().map(|c| c.size).sum();
+    let max_files = quota.max_files.unwrap_or(usize::MAX);
+    let max_size = quota.max_size_mb.map_or(u64::MAX, |mb| mb * 1024 * 1024);
+
+    match policy {
+        PrunePolicy::Oldest => candidates.sort_by_key(|c| c.modified),
+        PrunePolicy::LeastShown => candidates.sort_by_key(|c| (c.shown, c.modified)),
+        PrunePolicy::LowestFavorites => candidates.sort_by_key(|c| (c.favorites, c.modified)),
+    }
+
+    let (mut removed, mut freed) = (Vec::new(), 0);
+    for candidate in candidates {
+        if files <= max_files && size <= max_size {
+            break;
+        }
+        if protected.contains(&candidate.file_name) {
+            continue;
+        }
+        say!("Pruning {}", candidate.path.display());
+        if !dry_run {
+            fs::remove_file(&candidate.path)?;
+            db.mark_removed(&candidate.file_name)?;
+        }
+        files -= 1;
+        size -= candidate.size;
+        freed += candidate.size;
+        removed.push(candidate.path);
+    }
+    if files > max_files || size > max_size {
+        say!(
+            "{} is still over its quota, the current wallpaper is kept",
+            dir.display()
+        );
+    }
+    Ok((removed, freed))
+}
+
+/// Enforce the configured quotas on wallpaper_dir, `nsfw/` and `archive/`.
+///
+/// Favorites mirrored into `collections/` are not in any of these and never pruned, only
+/// `sync-collection --prune` removes them. The current wallpaper is never removed, which covers one just set with `download --apply`.
+pub fn prune(
+    wallpaper_dir: &Path,
+    db: &Database,
+    config: &Prune,
+    dry_run: bool,
+) -> Result<output::Pruned, MyError> {
+    let mut protected = HashSet::new();
+    if let Some(current) = db
+        .get_current()
+        .ok()
+        .and_then(|c| c.file_name().map(|f| f.to_owned()))
+    {
+        protected.insert(current.to_string_lossy().into_owned());
+    }
+
+    let dirs = [
+        (wallpaper_dir.to_path_buf(), &config.active),
+        (wallpaper_dir.join("nsfw"), &config.nsfw),
+        (wallpaper_dir.join("archive"), &config.archive),
+    ];
+    let (mut removed, mut freed) = (Vec::new(), 0);
+    for (dir, quota) in dirs {
+        if let Some(quota) = quota {
+            let (files, bytes) = prune_dir(&dir, quota, config.policy, &protected, db, dry_run)?;
+            removed.extend(files);
+            freed += bytes;
+        }
+    }
+
+    if !removed.is_empty() || dry_run {
+        say!(
+            "{} {} files, {:.1} MB",
+            if dry_run { "Would prune" } else { "Pruned" },
+            removed.len(),
+            freed as f64 / 1024.0 / 1024.0
+        );
+    }
+    Ok(output::Pruned {
+        dry_run,
+        removed,
+        freed_bytes: freed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_prune_least_shown() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::new(&dir.path().join("db")).unwrap();
+        let wallpaper_dir = dir.path().join("wallpapers");
+        fs::create_dir_all(&wallpaper_dir).unwrap();
+        for (name, shown) in [("a.jpg", 3), ("b.jpg", 0), ("c.jpg", 1), ("d.jpg", 2)] {
+            fs::write(wallpaper_dir.join(name), b"image").unwrap();
+            for _ in 0..shown {
+                db.record_shown(name).unwrap();
+            }
+        }
+        let config = Prune {
+            policy: PrunePolicy::LeastShown,
+            active: Some(Quota {
+                max_files: Some(2),
+                max_size_mb: None,
+            }),
+            ..Default::default()
+        };
+
+        let pruned = prune(&wallpaper_dir, &db, &config, true).unwrap();
+        assert_eq!(pruned.removed.len(), 2);
+        assert_eq!(image_files(&wallpaper_dir).len(), 4);
+
+        prune(&wallpaper_dir, &db, &config, false).unwrap();
+        let left: Vec<_> = image_files(&wallpaper_dir)
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(left, ["a.jpg", "d.jpg"]);
+        assert_eq!(db.shown_count("c.jpg").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prune_keeps_the_current_wallpaper() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::new(&dir.path().join("db")).unwrap();
+        let wallpaper_dir = dir.path().join("wallpapers");
+        fs::create_dir_all(&wallpaper_dir).unwrap();
+        for name in ["a.jpg", "b.jpg"] {
+            fs::write(wallpaper_dir.join(name), b"image").unwrap();
+        }
+        db.record_shown("b.jpg").unwrap();
+        db.set_current(&wallpaper_dir.join("a.jpg")).unwrap();
+        let config = Prune {
+            policy: PrunePolicy::LeastShown,
+            active: Some(Quota {
+                max_files: Some(1),
+                max_size_mb: None,
+            }),
+            ..Default::default()
+        };
+
+        let pruned = prune(&wallpaper_dir, &db, &config, false).unwrap();
+        assert_eq!(pruned.removed, [wallpaper_dir.join("b.jpg")]);
+    }
+}
@@ -0,0 +1,112 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use log::debug;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{Effect, Lockscreen, LockscreenStyle};
+use crate::effects::{self, TextContext};
+use crate::error::MyError;
+
+/// Turn `source` into a lockscreen image at `target`.
+///
+/// The format follows the extension of `target`, PNG when it has none, which is the
+/// only format i3lock reads. It is written next to `target` first and moved in place,
+/// so a locker starting meanwhile never sees half a file.
+pub fn generate(
+    source: &Path,
+    size: Option<(u32, u32)>,
+    config: &Lockscreen,
+    overlay: Option<(&[Effect], &TextContext)>,
+    target: &Path,
+) -> Result<(), MyError> {
+    let format = match target.extension() {
+        None => ImageFormat::Png,
+        Some(_) => ImageFormat::from_path(target)
+            .ok()
+            .filter(|format| format.writing_enabled())
+            .ok_or_else(|| {
+                MyError::ImageError(format!(
+                    "Cannot write lockscreen images as {}, use .png",
+                    target.display()
+                ))
+            })?,
+    };
+    let mut image = image::open(source)?;
+    if let Some((width, height)) = size {
+        image = image.resize_to_fill(width, height, FilterType::Lanczos3);
+    }
+
+    image = match config.style {
+        LockscreenStyle::Blur => image.fast_blur(config.blur_sigma),
+        LockscreenStyle::Pixelate => pixelate(&image, config.pixel_size),
+        LockscreenStyle::Plain => image,
+    };
+
+    if let Some((effects, context)) = overlay {
+        image = effects::apply_to_image(image, effects, context)?;
+    }
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = target.with_extension(format!("partial.{}", format.extensions_str()[0]));
+    DynamicImage::ImageRgb8(image.to_rgb8()).save_with_format(&partial, format)?;
+    fs::rename(&partial, target)?;
+    debug!("Lockscreen image written to {:?}", target);
+    Ok(())
+}
+
+fn pixelate(image: &DynamicImage, pixel_size: u32) -> DynamicImage {
+    let pixel_size = pixel_size.max(1);
+    let (width, height) = (image.width(), image.height());
+    image
+        .resize_exact(
+            (width / pixel_size).max(1),
+            (height / pixel_size).max(1),
+            FilterType::Triangle,
+        )
+        .resize_exact(width, height, FilterType::Nearest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pixelated_lockscreen() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("wallpaper.jpg");
+        RgbImage::from_fn(400, 300, |x, y| Rgb([(x % 256) as u8, (y % 256) as u8, 0]))
+            .save(&source)
+            .unwrap();
+        let target = dir.path().join("lock/lockscreen.png");
+        let config = Lockscreen {
+            style: LockscreenStyle::Pixelate,
+            pixel_size: 20,
+            ..Default::default()
+        };
+
+        generate(&source, Some((200, 100)), &config, None, &target).unwrap();
+
+        let image = image::open(&target).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (200, 100));
+        // Every 20x20 block is a single color.
+        assert_eq!(image.get_pixel(0, 0), image.get_pixel(19, 19));
+        assert!(!dir.path().join("lock/lockscreen.partial.png").exists());
+
+        let jpeg = dir.path().join("lock/lockscreen.jpg");
+        generate(&source, None, &config, None, &jpeg).unwrap();
+        assert_eq!(
+            image::ImageReader::open(&jpeg)
+                .unwrap()
+                .with_guessed_format()
+                .unwrap()
+                .format(),
+            Some(ImageFormat::Jpeg)
+        );
+        assert!(generate(&source, None, &config, None, &dir.path().join("lock.txt")).is_err());
+    }
+}
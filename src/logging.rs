@@ -0,0 +1,204 @@
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::{self, LogFormat, Logging};
+
+/// Levels from quietest to most verbose, stepped through by -q and -v.
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Set up logging to stderr at the configured level, moved one step per `verbose`
+/// and `quiet`, and to the log file when enabled.
+///
+/// Other crates only get through with warnings and errors, their debug output
+/// drowns ours.
+pub fn init(config: &Logging, verbose: u8, quiet: u8) {
+    let configured = config.level.parse().unwrap_or(LevelFilter::Warn);
+    let level = step(configured, verbose, quiet);
+    let file = config
+        .file
+        .then(|| {
+            let path = config
+                .path
+                .as_ref()
+                .map_or_else(config::default_log_path, PathBuf::from);
+            LogFile::open(&path, config.max_size_kb * 1024, config.keep)
+                .map_err(|e| eprintln!("Not logging to {}: {}", path.display(), e))
+                .ok()
+        })
+        .flatten();
+    let file_level = match file {
+        Some(_) => config.file_level.parse().unwrap_or(LevelFilter::Info),
+        None => LevelFilter::Off,
+    };
+
+    let logger = Logger {
+        level,
+        format: config.format,
+        file: file.map(Mutex::new),
+        file_level,
+    };
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(level.max(file_level));
+    }
+}
+
+fn step(level: LevelFilter, verbose: u8, quiet: u8) -> LevelFilter {
+    let index = LEVELS.iter().position(|l| *l == level).unwrap_or(2) as i32;
+    let index = (index + verbose as i32 - quiet as i32).clamp(0, LEVELS.len() as i32 - 1);
+    LEVELS[index as usize]
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+    file: Option<Mutex<LogFile>>,
+    file_level: LevelFilter,
+}
+
+impl Logger {
+    fn line(&self, record: &Record, timestamp: bool) -> String {
+        match self.format {
+            LogFormat::Json => serde_json::json!({
+                "time": Local::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+            LogFormat::Text if timestamp => format!(
+                "{} {:<5} {}: {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Text => format!("{:<5} {}", record.level(), record.args()),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            || metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.level {
+            eprintln!("{}", self.line(record, false));
+        }
+        if let (Some(file), true) = (&self.file, record.level() <= self.file_level) {
+            let line = self.line(record, true);
+            if let Err(e) = file.lock().unwrap().write(&line) {
+                eprintln!("Failed to write the log file: {}", e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+/// A log file that is moved to `<path>.1` once it reaches `max_size`, keeping `keep`
+/// older files.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        let _ = fs::remove_file(numbered(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(numbered(n), numbered(n + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_levels_and_rotation() {
+        assert_eq!(step(LevelFilter::Warn, 0, 0), LevelFilter::Warn);
+        assert_eq!(step(LevelFilter::Warn, 2, 0), LevelFilter::Debug);
+        assert_eq!(step(LevelFilter::Warn, 9, 0), LevelFilter::Trace);
+        assert_eq!(step(LevelFilter::Warn, 0, 1), LevelFilter::Error);
+        assert_eq!(step(LevelFilter::Info, 1, 5), LevelFilter::Off);
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("wallpaper.log");
+        let mut file = LogFile::open(&path, 20, 2).unwrap();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("wallpaper.log.1")).unwrap(),
+            "third line\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("wallpaper.log.2")).unwrap(),
+            "second line\n"
+        );
+        assert!(!dir.path().join("wallpaper.log.3").exists());
+    }
+}
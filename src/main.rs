@@ -1,30 +1,65 @@
-use dirs;
 use log::debug;
 use rand::Rng;
 use regex::Regex;
-use serde_json::Value;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 
+#[macro_use]
+mod output;
+
 mod error;
 use error::MyError;
 
 mod config;
 mod database;
+mod dynamic;
+mod effects;
+mod layers;
+mod library;
+mod lockscreen;
+mod logging;
+mod phash;
+mod render;
+mod schedule;
+mod setter;
+mod setup;
+mod sources;
 mod wallhaven;
+#[cfg(feature = "wayland")]
+mod wayland;
 
-use config::Config;
+use config::{Config, ConfigError};
 use database::Database;
-use wallhaven::Wallpaper;
+use sources::WallpaperSource;
+use wallhaven::{WallHaven, Wallpaper};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "wallpaper")]
 struct Opt {
+    /// Config file [default: $XDG_CONFIG_HOME/sinh-x/wallpaper/config.toml]
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        global = true,
+        env = "SINH_WALLPAPER_CONFIG"
+    )]
+    config: Option<PathBuf>,
+    /// Log more, repeat for even more (-vv for debug messages)
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+    /// Log less, -q only shows errors and -qq nothing
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    quiet: u8,
+    /// text, or json for one JSON object with the result on stdout and progress in the log
+    #[structopt(long, global = true, default_value = "text")]
+    output: output::Format,
+    /// Override a config value, e.g. --set daemon.interval=10
+    #[structopt(long = "set", global = true, number_of_values = 1)]
+    set: Vec<String>,
     /// Command to execute
     #[structopt(subcommand)]
     cmd: Command,
@@ -37,9 +72,56 @@ enum Command {
         /// Path to the wallpaper
         #[structopt(short, long, parse(from_os_str))]
         path: Option<PathBuf>,
+        /// Effects profile to apply, overrides effects.profile from the config
+        #[structopt(long)]
+        profile: Option<String>,
+        /// swww transition type, overrides the [swww] section
+        #[structopt(long)]
+        transition: Option<String>,
+    },
+    /// Download new wallpapers from the configured search, or specific ones by id or URL
+    Download {
+        /// Where to download from: wallhaven, unsplash, reddit, bing, apod, feed or folder
+        #[structopt(short, long, default_value = "wallhaven")]
+        source: String,
+        /// Id on the source to download, can be repeated
+        #[structopt(long = "id")]
+        ids: Vec<String>,
+        /// Links or ids, e.g. https://wallhaven.cc/w/<id> or https://whvn.cc/<id>
+        urls: Vec<String>,
+        /// Read ids or URLs from a file, one per line, "-" for stdin
+        #[structopt(short, long, parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// Set the last downloaded wallpaper right away
+        #[structopt(short, long)]
+        apply: bool,
+    },
+    /// Write a complete config file, asking for the basics
+    Setup {
+        /// Do not ask, use the flags and defaults
+        #[structopt(long)]
+        non_interactive: bool,
+        /// Replace an existing config file
+        #[structopt(long)]
+        force: bool,
+        #[structopt(long)]
+        wallpaper_dir: Option<String>,
+        /// swww, feh or wayland [default: detected]
+        #[structopt(long)]
+        wallpaper_app: Option<String>,
+        /// sfw, sketchy or nsfw
+        #[structopt(long)]
+        purity: Option<String>,
+        #[structopt(long)]
+        api_key: Option<String>,
+        #[structopt(long)]
+        query: Option<String>,
+    },
+    /// Inspect the config file
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
     },
-    Download,
-    Setup,
     Archive {
         #[structopt(short, long, parse(from_os_str))]
         dir: Option<PathBuf>,
@@ -47,198 +129,887 @@ enum Command {
         archive_dir: Option<PathBuf>,
     },
     Current,
+    /// Remove near-duplicate images from the library, keeping the best resolution
+    Dedupe {
+        /// Only list what would be removed
+        #[structopt(long)]
+        dry_run: bool,
+        /// Maximum number of differing hash bits [default: dedupe.threshold from the config]
+        #[structopt(short, long)]
+        threshold: Option<u32>,
+    },
+    /// Delete wallpapers until every directory fits its quota from the [prune] section
+    Prune {
+        /// Only list what would be removed
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Mirror a Wallhaven collection into a sub directory of wallpaper_dir
+    SyncCollection {
+        /// Owner of the collection
+        #[structopt(short, long)]
+        user: String,
+        /// Collection id, as in https://wallhaven.cc/user/<user>/favorites/<id>
+        #[structopt(short, long)]
+        id: u64,
+        /// Sub directory of wallpaper_dir to mirror into [default: collections/<id>]
+        #[structopt(short, long)]
+        dir: Option<String>,
+        /// Remove local files that were removed from the collection
+        #[structopt(long)]
+        prune: bool,
+    },
+    /// Keep running, changing the wallpaper every daemon.interval minutes and following
+    /// the frames of dynamic sets
+    Daemon,
+    /// Write a blurred or pixelated copy of the current wallpaper for the screen locker
+    Lockscreen {
+        /// Where to write the image [default: lockscreen.path from the config]
+        #[structopt(short, long, parse(from_os_str))]
+        target: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Report every problem in the config file
+    Check,
+    /// Print the config merged from every layer
+    Show {
+        /// Also print which layer set each value
+        #[structopt(long)]
+        resolved: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), MyError> {
     let opt = Opt::from_args();
+    output::set_format(opt.output);
 
-    let config_path: PathBuf = dirs::home_dir().unwrap();
-    let config_path = config_path.join(".config/sinh-x/wallpaper/config.toml");
-    let config = Config::new(&config_path.display().to_string()).expect("Failed to load config");
-    config.validate().expect("Invalid config");
+    let explicit = opt.config.is_some();
+    let config_path = opt.config.unwrap_or_else(config::default_config_path);
+    let load = || layers::load(&config_path, explicit, &opt.set);
+    if matches!(opt.cmd, Command::Setup { .. } | Command::Config { .. }) {
+        let terminal_only = config::Logging {
+            file: false,
+            ..Default::default()
+        };
+        logging::init(&terminal_only, opt.verbose, opt.quiet);
+    }
+    match opt.cmd {
+        Command::Setup {
+            non_interactive,
+            force,
+            wallpaper_dir,
+            wallpaper_app,
+            purity,
+            api_key,
+            query,
+        } => {
+            let answers = setup::Answers {
+                wallpaper_dir,
+                wallpaper_app,
+                purity,
+                api_key,
+                query,
+            };
+            return setup::run(&config_path, answers, !non_interactive, force);
+        }
+        Command::Config {
+            cmd: ConfigCommand::Check,
+        } => return check_config(&config_path, load()),
+        Command::Config {
+            cmd: ConfigCommand::Show { resolved },
+        } => {
+            let layers = load().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            show_config(&layers, resolved);
+            return Ok(());
+        }
+        _ => {}
+    }
+    let load_config = || {
+        load()
+            .and_then(|layers| layers.config())
+            .and_then(|config| config.validate().map(|()| config))
+    };
+    let config = load_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    logging::init(&config.log, opt.verbose, opt.quiet);
 
-    let binding = &config.database.unwrap();
-    let db_path = Path::new(&binding.database_path);
-    let db = Database::new(&db_path).unwrap();
+    if let Command::Daemon = opt.cmd {
+        let files = load().map(|layers| layers.files()).unwrap_or_default();
+        daemon(config, &files, load_config).await;
+        return Ok(());
+    }
+    let db = open_database(&config)?;
 
     match opt.cmd {
-        Command::Refresh { path } => refresh(path.as_deref())?,
-        Command::Download => {
-            let wallhaven = wallhaven::WallHaven::new(
-                &config.download.api_key,
-                &config.download.purity,
-                &config.download.categories,
-                &"2880x1800",
-                &config.download.query,
-                &config.general.wallpaper_dir,
+        Command::Refresh {
+            path,
+            profile,
+            transition,
+        } => {
+            let refreshed = refresh(
+                &config,
+                path.as_deref(),
+                profile.as_deref(),
+                transition.as_deref(),
                 &db,
-            );
+            )?;
+            output::print(&refreshed, |_| {});
+            #[cfg(feature = "wayland")]
+            wayland::wait();
+        }
+        Command::Download {
+            source,
+            ids,
+            urls,
+            file,
+            apply,
+        } => {
+            let source = wallpaper_source(&source, &config, &db)?;
+            let dedupe = config
+                .dedupe
+                .reject_on_download
+                .then_some(config.dedupe.threshold);
+
+            let mut inputs = ids;
+            inputs.extend(urls);
+            if let Some(file) = file {
+                let contents = if file == Path::new("-") {
+                    std::io::read_to_string(std::io::stdin())?
+                } else {
+                    fs::read_to_string(&file)?
+                };
+                inputs.extend(
+                    contents
+                        .lines()
+                        .map(str::trim)
+                        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                        .map(String::from),
+                );
+            }
+
+            let mut result = output::Downloaded {
+                source: source.name().to_string(),
+                ..Default::default()
+            };
+            let mut failures = Vec::new();
+            let mut failed = |input: &str, error: String| {
+                log::error!("{}", error);
+                failures.push(output::Failure {
+                    input: input.to_string(),
+                    error,
+                });
+            };
+
+            if !inputs.is_empty() {
+                let mut downloaded = Vec::new();
+                for input in &inputs {
+                    let Some(id) = source.parse_id(input) else {
+                        failed(
+                            input,
+                            format!("Not a {} id or URL: {}", source.name(), input),
+                        );
+                        continue;
+                    };
+                    let download_location = &config.general.wallpaper_dir;
+                    match sources::download_one(&*source, &db, download_location, &id, dedupe).await
+                    {
+                        Ok(path) => downloaded.push(path),
+                        Err(e) => failed(input, format!("Failed to download {}: {}", id, e)),
+                    }
+                }
+                if let (true, Some(path)) = (apply, downloaded.last()) {
+                    match refresh(&config, Some(path), None, None, &db) {
+                        Ok(_) => result.applied = Some(path.clone()),
+                        Err(e) => failed(
+                            &path.display().to_string(),
+                            format!("Failed to apply {}: {}", path.display(), e),
+                        ),
+                    }
+                }
+                result.downloaded = downloaded;
+                let dir = PathBuf::from(&config.general.wallpaper_dir);
+                if let Err(e) = library::prune(&dir, &db, &config.prune, false) {
+                    failed("prune", format!("Failed to prune the library: {}", e));
+                }
+                result.failed = failures;
+                output::print(&result, |_| {});
+                #[cfg(feature = "wayland")]
+                wayland::wait();
+                return Ok(());
+            }
 
-            match wallhaven.download().await {
-                Ok(_) => println!("Downloaded wallpapers successfully"),
-                Err(e) => eprintln!("Failed to download wallpapers: {}", e),
+            let downloaded = sources::download(
+                &*source,
+                &db,
+                &config.general.wallpaper_dir,
+                10,
+                config.download_purity(),
+                dedupe,
+            )
+            .await;
+            match downloaded {
+                Ok(paths) => {
+                    say!("Downloaded wallpapers successfully");
+                    result.downloaded = paths;
+                }
+                Err(e) => failed("search", format!("Failed to download wallpapers: {}", e)),
+            }
+            let dir = PathBuf::from(&config.general.wallpaper_dir);
+            if let Err(e) = library::prune(&dir, &db, &config.prune, false) {
+                failed("prune", format!("Failed to prune the library: {}", e));
             }
+            result.failed = failures;
             match db.load_from_db() {
                 Ok(wallpapers) => {
                     for wallpaper in &wallpapers {
                         debug!("{:?}", wallpaper.url);
                     }
-                    println!("Total {} wallpapers in the database", wallpapers.len());
+                    say!("Total {} wallpapers in the database", wallpapers.len());
                 }
-                Err(e) => eprintln!("Failed to load wallpapers from the database: {}", e),
+                Err(e) => log::error!("Failed to load wallpapers from the database: {}", e),
             }
+            output::print(&result, |_| {});
+        }
+        Command::SyncCollection {
+            user,
+            id,
+            dir,
+            prune,
+        } => {
+            let wallhaven = wallhaven(&config, &db)?;
+
+            let dir = dir.unwrap_or_else(|| format!("collections/{}", id));
+            let synced = wallhaven.sync_collection(&user, id, &dir, prune).await?;
+            output::print(&synced, |_| {});
+            if !synced.failed.is_empty() {
+                return Err(MyError::SourceError(format!(
+                    "{} wallpapers of collection {} could not be downloaded",
+                    synced.failed.len(),
+                    synced.collection
+                )));
+            }
+        }
+        Command::Dedupe { dry_run, threshold } => {
+            let threshold = threshold.unwrap_or(config.dedupe.threshold);
+            let dir = PathBuf::from(&config.general.wallpaper_dir);
+            let groups = library::dedupe(&dir, &db, threshold, dry_run)?
+                .into_iter()
+                .map(|mut group| output::DuplicateGroup {
+                    keep: group.remove(0),
+                    remove: group,
+                })
+                .collect();
+            output::print(&output::Deduped { dry_run, groups }, |_| {});
+        }
+        Command::Prune { dry_run } => {
+            let dir = PathBuf::from(&config.general.wallpaper_dir);
+            let pruned = library::prune(&dir, &db, &config.prune, dry_run)?;
+            output::print(&pruned, |_| {});
+        }
+        Command::Lockscreen { target } => {
+            let current = db
+                .get_current()
+                .map_err(|_| MyError::DatabaseError("No wallpaper has been set yet".to_string()))?;
+            let current = dynamic::resolve(&current, chrono::Local::now().naive_local())?;
+            let path = target.unwrap_or_else(|| lockscreen_path(&config));
+            lockscreen(&config, &current, &path, &db)?;
+            output::print(&output::Lockscreen { path }, |lockscreen| {
+                println!("{}", lockscreen.path.display())
+            });
+        }
+        Command::Setup { .. } | Command::Config { .. } | Command::Daemon => {
+            unreachable!("handled before the database is opened")
         }
-        Command::Setup => setup()?,
         Command::Archive { dir, archive_dir } => {
             let dir = dir.unwrap_or_else(|| PathBuf::from(&config.general.wallpaper_dir));
             let archive_dir = archive_dir
                 .unwrap_or_else(|| PathBuf::from(&config.general.wallpaper_dir).join("archive"));
-            archive(dir, archive_dir)?;
+            let archived = archive(dir, archive_dir)?;
+            output::print(&archived, |archived| {
+                println!(
+                    "Archived {} files. Total files in archive: {}",
+                    archived.archived.len(),
+                    archived.total
+                );
+            });
         }
         Command::Current => {
-            // Read the ~/.fehbg file
-            let fehbg = fs::read_to_string(Path::new(&format!(
-                "{}/.fehbg",
-                dirs::home_dir().unwrap().to_str().unwrap()
+            let current = current(&config, &db).await?;
+            output::print(&current, |current| {
+                println!("{}", current.file_name);
+                if let Some(frame) = &current.frame {
+                    println!("dynamic");
+                    println!("{}", frame.display());
+                    return;
+                }
+                if let Some(id) = &current.id {
+                    println!("{}", id);
+                }
+                match current.source.as_deref() {
+                    Some("wallhaven") => current.tags.iter().for_each(|tag| println!("{}", tag)),
+                    Some(source) => println!("{}", source),
+                    None => {}
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn wallhaven(config: &Config, db: &Database) -> Result<WallHaven, MyError> {
+    let api_key = config.download.api_key()?;
+    if api_key.is_empty() {
+        debug!("No Wallhaven API key, only public wallpapers are available");
+    }
+    let query = config
+        .download
+        .search_query(config.download_purity())
+        .at_least("2880x1800");
+    Ok(
+        WallHaven::new(&api_key, query, &config.general.wallpaper_dir, db)
+            .with_base_url(config.download.base_url()),
+    )
+}
+
+fn wallpaper_source(
+    name: &str,
+    config: &Config,
+    db: &Database,
+) -> Result<Box<dyn WallpaperSource>, MyError> {
+    let sources = &config.sources;
+    let missing = |section: &str| {
+        MyError::SourceError(format!(
+            "The '[sources.{}]' section is missing in the config file",
+            section
+        ))
+    };
+    let source: Box<dyn WallpaperSource> = match name {
+        "wallhaven" => Box::new(wallhaven(config, db)?),
+        "unsplash" => Box::new(sources::unsplash::Unsplash::new(
+            sources
+                .unsplash
+                .as_ref()
+                .ok_or_else(|| missing("unsplash"))?,
+        )),
+        "reddit" => Box::new(sources::reddit::Reddit::new(
+            sources.reddit.as_ref().ok_or_else(|| missing("reddit"))?,
+        )),
+        "bing" => Box::new(sources::bing::Bing::new(sources.bing.as_ref())),
+        "apod" => Box::new(sources::apod::Apod::new(sources.apod.as_ref())),
+        "feed" => Box::new(sources::feed::Feed::new(
+            sources.feed.as_ref().ok_or_else(|| missing("feed"))?,
+        )),
+        "folder" => Box::new(sources::folder::Folder::new(
+            sources.folder.as_ref().ok_or_else(|| missing("folder"))?,
+        )),
+        _ => {
+            return Err(MyError::SourceError(format!(
+                "Unknown source '{}'. It must be one of wallhaven, unsplash, reddit, bing, apod, feed or folder",
+                name
             )))
-            .unwrap();
+        }
+    };
+    Ok(source)
+}
+
+fn refresh(
+    config: &Config,
+    path: Option<&Path>,
+    profile: Option<&str>,
+    transition: Option<&str>,
+    db: &Database,
+) -> Result<output::Refreshed, MyError> {
+    say!("Setting wallpaper...");
 
+    if let Some(transition) = transition {
+        config::validate_transition_type(transition, "--transition")
+            .map_err(|e| ConfigError::Invalid(vec![e]))?;
+    }
+
+    let mut wallpaper_dir = PathBuf::from(&config.general.wallpaper_dir);
+
+    let mut rule_name = None;
+    let wallpaper = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let rule = schedule::active_rule(&config.schedule, &chrono::Local::now());
+            if let Some(dir) = rule.and_then(|rule| rule.dir.as_ref()) {
+                wallpaper_dir = wallpaper_dir.join(dir);
+            }
+            let purity = config.general.purity;
+            let mut wallpapers: Vec<_> = library::wallpapers(&wallpaper_dir)
+                .into_iter()
+                .filter(|path| purity.contains(library::purity_of(db, path)))
+                .collect();
+            wallpapers.extend(dynamic::sets(&wallpaper_dir));
+            if let Some(rule) = rule {
+                let name = rule.name.as_deref().unwrap_or("unnamed");
+                say!("Using schedule rule '{}'", name);
+                rule_name = Some(name.to_string());
+                let selected = schedule::select(wallpapers.clone(), rule, db);
+                if selected.is_empty() {
+                    log::warn!("No wallpaper matches the schedule rule, picking from all");
+                } else {
+                    wallpapers = selected;
+                }
+            }
+            if wallpapers.is_empty() {
+                return Err(MyError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "No wallpapers to pick from",
+                )));
+            }
+            let mut rng = rand::thread_rng();
+            wallpapers[rng.gen_range(0..wallpapers.len())].clone()
+        }
+    };
+    say!("Setting wallpaper: {}", wallpaper.display());
+    log::info!("Showing {}", wallpaper.display());
+    show(config, &wallpaper, profile, transition, db)?;
+
+    if let Some(file_name) = wallpaper.file_name() {
+        db.record_shown(&file_name.to_string_lossy())?;
+    }
+    db.set_current(&wallpaper)?;
+    Ok(output::Refreshed {
+        wallpaper,
+        rule: rule_name,
+    })
+}
+
+/// The wallpaper set last, from the database or else from ~/.fehbg.
+async fn current(config: &Config, db: &Database) -> Result<output::Current, MyError> {
+    let not_set = || MyError::DatabaseError("No wallpaper has been set yet".to_string());
+    let path = match db.get_current() {
+        Ok(path) => path,
+        Err(_) => {
             // Extract the wallpaper path from the fehbg file
+            let fehbg = dirs::home_dir().ok_or_else(not_set)?.join(".fehbg");
+            let fehbg = fs::read_to_string(fehbg).map_err(|_| not_set())?;
             let re = Regex::new(r"'(.*?)'").unwrap();
-            let caps = re.captures(&fehbg).unwrap();
-            let path = caps.get(1).map_or("", |m| m.as_str());
+            let caps = re.captures(&fehbg).ok_or_else(not_set)?;
+            PathBuf::from(&caps[1])
+        }
+    };
+    let file_name = path
+        .file_name()
+        .map_or_else(String::new, |f| f.to_string_lossy().into_owned());
 
-            // Extract the file name from the wallpaper path
-            let path = Path::new(path);
-            let file_name = path.file_name().unwrap().to_str().unwrap();
+    if dynamic::is_dynamic(&path) {
+        let frame = dynamic::resolve(&path, chrono::Local::now().naive_local())?;
+        return Ok(output::Current {
+            path,
+            file_name,
+            dynamic: true,
+            frame: Some(frame),
+            ..Default::default()
+        });
+    }
 
-            let wallpaper = db.get_wallpaper_details(file_name).unwrap();
+    let wallpaper = db.get_wallpaper_details(&file_name).ok();
+    let source = db.get_source(&file_name).ok();
+    if let (Some(wallpaper), Some("wallhaven")) = (&wallpaper, source.as_deref()) {
+        // Tags are stored by the detail endpoint, fetch them once if they are missing
+        if db.get_tags(&file_name).is_err() {
+            wallhaven(config, db)?.fetch_metadata(&wallpaper.id).await?;
+        }
+    }
+    Ok(output::Current {
+        id: wallpaper.as_ref().map(|w| w.id.clone()),
+        purity: wallpaper.map(|w| w.purity),
+        tags: db.get_tags(&file_name).unwrap_or_default(),
+        source,
+        path,
+        file_name,
+        ..Default::default()
+    })
+}
 
-            println!("{}", file_name);
+/// Put `wallpaper` on screen, or the frame for the current time if it is a dynamic set.
+fn show(
+    config: &Config,
+    wallpaper: &Path,
+    profile: Option<&str>,
+    transition: Option<&str>,
+    db: &Database,
+) -> Result<(), MyError> {
+    let fade = dynamic::is_dynamic(wallpaper);
+    let original = dynamic::resolve(wallpaper, chrono::Local::now().naive_local())?;
+    if fade {
+        say!("Current frame: {}", original.display());
+    }
+    let wallpaper = if config.render.enabled {
+        rendition(config, &original)
+    } else {
+        original.clone()
+    };
+    let wallpaper = match profile.or(config.effects.profile.as_deref()) {
+        Some(profile) => with_effects(config, profile, &original, &wallpaper, db),
+        None => wallpaper,
+    };
 
-            println!("{}", wallpaper.id);
+    setter::set(config, &wallpaper, transition, fade)?;
 
-            let client = reqwest::Client::new();
-            let url = format!("https://wallhaven.cc/api/v1/w/{}", wallpaper.id);
-            let res = client.get(url).send().await?;
-            let body: Value = res.json().await?;
-            let tags = &body["data"]["tags"];
-            for tag in tags.as_array().unwrap() {
-                println!("{}", tag["name"].as_str().unwrap());
-            }
+    if config.lockscreen.on_refresh {
+        let target = lockscreen_path(config);
+        match lockscreen(config, &original, &target, db) {
+            Ok(()) => say!("Lockscreen image updated: {}", target.display()),
+            Err(e) => log::error!("Failed to update the lockscreen image: {}", e),
         }
     }
 
     Ok(())
 }
 
-fn refresh(path: Option<&Path>) -> Result<(), MyError> {
-    println!("Setting wallpaper...");
+/// Open the database in `database.database_path`.
+///
+/// sled locks it for one process at a time, so this waits a few seconds for another
+/// command or a daemon tick to finish with it.
+fn open_database(config: &Config) -> Result<Database, MyError> {
+    let path = &config
+        .database
+        .as_ref()
+        .ok_or_else(|| ConfigError::Invalid(vec!["database.database_path is missing".to_string()]))?
+        .database_path;
+    let started = std::time::Instant::now();
+    loop {
+        match Database::new(Path::new(path)) {
+            // sled has no error kind for this, only the message
+            Err(database::DatabaseError::SledError(sled::Error::Io(e)))
+                if e.to_string().contains("could not acquire lock")
+                    && started.elapsed() < Duration::from_secs(5) =>
+            {
+                debug!("Waiting for the database: {}", e);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
 
-    let config = Config::new("/home/sinh/.config/sinh-x/wallpaper/config.toml")
-        .expect("Failed to load config");
-    config.validate().expect("Invalid config");
+/// Rotate wallpapers and follow dynamic sets until stopped.
+///
+/// Saved changes to the config `files` are picked up between ticks. A config that
+/// does not load or validate is reported and the running one is kept. Images added
+/// to the [sources.folder] folder are imported as they arrive.
+///
+/// The database is only open during a tick, so other commands can use it in between.
+async fn daemon(
+    config: Config,
+    files: &[PathBuf],
+    reload: impl Fn() -> Result<Config, ConfigError>,
+) {
+    const TICK: Duration = Duration::from_secs(60);
+    let mut config = std::sync::Arc::new(config);
 
-    let mut wallpaper_dir = PathBuf::from(&config.general.wallpaper_dir);
-    if config.general.purity.as_deref() == Some("nsfw") {
-        wallpaper_dir = wallpaper_dir.join("nsfw");
+    let (changed, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let _watcher = layers::watch(files, move || {
+        let _ = changed.send(());
+    })
+    .map_err(|e| log::warn!("Not watching the config for changes: {}", e))
+    .ok();
+
+    let (arrived, mut arrivals) = tokio::sync::mpsc::unbounded_channel();
+    let watch_folder = |config: &Config| {
+        let folder = config.sources.folder.as_ref()?;
+        let arrived = arrived.clone();
+        sources::folder::Folder::new(folder)
+            .watch(move || {
+                let _ = arrived.send(());
+            })
+            .map_err(|e| log::warn!("Not watching {} for new wallpapers: {}", folder.path, e))
+            .ok()
+    };
+    let mut _folder_watcher = watch_folder(&config);
+    import_folder(&config).await;
+
+    let mut last_rotation: Option<std::time::Instant> = None;
+    let mut frame = None;
+    loop {
+        let interval = Duration::from_secs(config.daemon.interval * 60);
+        let rotate = !interval.is_zero() && last_rotation.is_none_or(|t| t.elapsed() >= interval);
+        let (tick_config, shown) = (config.clone(), frame.clone());
+        match blocking(move || daemon_tick(&tick_config, rotate, shown)).await {
+            Ok(next) => frame = next,
+            Err(e) => log::error!("Skipping this tick: {}", e),
+        }
+        if rotate {
+            last_rotation = Some(std::time::Instant::now());
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(TICK) => {}
+            Some(()) = changes.recv() => {
+                // Editors often save in several writes, wait for the last one
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                while changes.try_recv().is_ok() {}
+                match reload() {
+                    Ok(new) => {
+                        config = std::sync::Arc::new(new);
+                        _folder_watcher = watch_folder(&config);
+                        log::info!("Reloaded the config");
+                    }
+                    Err(e) => log::error!("Keeping the previous config: {}", e),
+                }
+            }
+            Some(()) = arrivals.recv() => {
+                // Give copies and sync clients time to finish writing
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                while arrivals.try_recv().is_ok() {}
+                import_folder(&config).await;
+            }
+        }
     }
+}
 
-    let wallpaper = match path {
-        Some(path) => path.to_path_buf(),
-        None => {
-            let entries = std::fs::read_dir(wallpaper_dir)?;
-            let wallpapers: Vec<_> = entries
-                .filter_map(Result::ok)
-                .filter(|e| e.path().is_file())
-                .map(|e| e.path())
-                .collect();
-            let mut rng = rand::thread_rng();
-            wallpapers[rng.gen_range(0..wallpapers.len())].clone()
+/// One daemon tick: a new wallpaper when it is time to `rotate`, otherwise the next frame
+/// of a dynamic set once it differs from the `shown` one. Returns the frame on screen.
+fn daemon_tick(
+    config: &Config,
+    rotate: bool,
+    shown: Option<PathBuf>,
+) -> Result<Option<PathBuf>, MyError> {
+    let db = open_database(config)?;
+    let now = chrono::Local::now().naive_local();
+    let current_frame = || {
+        db.get_current()
+            .ok()
+            .and_then(|current| dynamic::resolve(&current, now).ok())
+    };
+    if rotate {
+        if let Err(e) = refresh(config, None, None, None, &db) {
+            log::error!("Failed to refresh wallpaper: {}", e);
         }
+        return Ok(current_frame());
+    }
+    let Ok(current) = db.get_current() else {
+        return Ok(shown);
     };
-    println!("Setting wallpaper: {}", wallpaper.display());
-
-    match config.general.wallpaper_app.as_str() {
-        "feh" => {
-            println!("Setting wallpaper using feh...");
-            let output = std::process::Command::new("feh")
-                .arg("--bg-max")
-                .arg("--image-bg #000000")
-                .arg(format!("{}", wallpaper.display()))
-                .output()?;
-
-            let command_str = format!("feh --bg-scale {}", wallpaper.display());
-            println!("Command: {}", command_str);
-
-            if output.status.success() {
-                println!("Wallpaper set successfully");
-            } else {
-                eprintln!(
-                    "Failed to set wallpaper: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            }
+    let next = current_frame();
+    // The first tick after a start only notes the frame, it is already on screen
+    if dynamic::is_dynamic(&current) && shown.is_some() && next != shown {
+        if let Err(e) = show(config, &current, None, None, &db) {
+            log::error!("Failed to show the next frame: {}", e);
         }
-        "swww" => {
-            println!("Setting wallpaper using swww...");
-            // Set the wallpaper as the desktop background.
-            // This depends on your desktop environment. For example, on GNOME:
-            let output = std::process::Command::new("swww")
-                .arg("img")
-                .arg(format!("{}", wallpaper.display()))
-                .output()?;
-            let command_str = format!("swww img {}", wallpaper.display());
-            println!("Command: {}", command_str);
-
-            if output.status.success() {
-                println!("Wallpaper set successfully");
-            } else {
-                eprintln!(
-                    "Failed to set wallpaper: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            }
+    }
+    Ok(next)
+}
+
+/// Run image processing and wallpaper apps without holding up the async runtime.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, MyError> + Send + 'static,
+) -> Result<T, MyError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| Err(MyError::Io(std::io::Error::other(e))))
+}
+
+/// Import new images from the [sources.folder] folder, if there is one.
+async fn import_folder(config: &std::sync::Arc<Config>) {
+    let Some(folder) = &config.sources.folder else {
+        return;
+    };
+    let dedupe = config
+        .dedupe
+        .reject_on_download
+        .then_some(config.dedupe.threshold);
+    let opened = config.clone();
+    let imported = match blocking(move || open_database(&opened)).await {
+        Ok(db) => {
+            sources::folder::Folder::new(folder)
+                .import(&db, &config.general.wallpaper_dir, dedupe)
+                .await
         }
-        &_ => {
-            println!("Unknown wallpaper app");
+        Err(e) => Err(e),
+    };
+    match imported {
+        Ok(paths) if !paths.is_empty() => {
+            log::info!("Imported {} wallpapers from {}", paths.len(), folder.path)
         }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to import from {}: {}", folder.path, e),
     }
+}
 
-    Ok(())
+/// Output-sized copy of `wallpaper` as configured in [render], or the original if that fails.
+fn rendition(config: &Config, wallpaper: &Path) -> PathBuf {
+    let Some(size) = output_size(config) else {
+        log::warn!("Could not determine the output size, using the original image");
+        return wallpaper.to_path_buf();
+    };
+    match render::render(wallpaper, size, config.render.mode, &cache_dir(config)) {
+        Ok(rendition) => {
+            say!(
+                "Rendered for {}x{}: {}",
+                size.0,
+                size.1,
+                rendition.display()
+            );
+            rendition
+        }
+        Err(e) => {
+            log::error!("Failed to render wallpaper, using the original: {}", e);
+            wallpaper.to_path_buf()
+        }
+    }
 }
 
-fn setup() -> Result<(), MyError> {
-    println!("Setting up...");
+fn output_size(config: &Config) -> Option<(u32, u32)> {
+    match &config.render.output {
+        Some(output) => render::parse_size(output),
+        None => render::detect_output_size(&config.general.wallpaper_app),
+    }
+}
 
-    let mut config_path: PathBuf = dirs::home_dir().unwrap();
-    config_path.push(".config/sinh-x/wallpaper");
+fn cache_dir(config: &Config) -> PathBuf {
+    config
+        .render
+        .cache_dir
+        .as_ref()
+        .map_or_else(render::default_cache_dir, PathBuf::from)
+}
 
-    // Create the directory if it does not exist
-    std::fs::create_dir_all(&config_path)?;
+/// `wallpaper` with the effects of `profile` applied, or unchanged if that fails.
+fn with_effects(
+    config: &Config,
+    profile: &str,
+    original: &Path,
+    wallpaper: &Path,
+    db: &Database,
+) -> PathBuf {
+    let Some(effects) = config.effects.profiles.get(profile) else {
+        log::warn!("Unknown effects profile '{}'", profile);
+        return wallpaper.to_path_buf();
+    };
+    let context = text_context(original, db);
 
-    config_path.push("config.toml");
+    match effects::apply(wallpaper, effects, &context, &cache_dir(config)) {
+        Ok(processed) => {
+            say!("Applied effects '{}': {}", profile, processed.display());
+            processed
+        }
+        Err(e) => {
+            log::error!("Failed to apply effects '{}': {}", profile, e);
+            wallpaper.to_path_buf()
+        }
+    }
+}
 
-    if !config_path.exists() {
-        let mut file = File::create(&config_path)?;
-        write!(file, "api_key = \"your_api_key\"\n")?;
-    } else {
-        let config = fs::read_to_string(&config_path)?;
-        println!("Config: {}", config);
+fn text_context(original: &Path, db: &Database) -> effects::TextContext {
+    let file = original
+        .file_name()
+        .map_or_else(String::new, |f| f.to_string_lossy().into_owned());
+    effects::TextContext {
+        tags: db.get_tags(&file).unwrap_or_default(),
+        file,
     }
+}
 
+fn lockscreen_path(config: &Config) -> PathBuf {
+    match &config.lockscreen.path {
+        Some(path) => PathBuf::from(path),
+        None => cache_dir(config).join("lockscreen.png"),
+    }
+}
+
+/// Lockscreen image of the original `wallpaper`, sized for the output when it can be found.
+fn lockscreen(
+    config: &Config,
+    wallpaper: &Path,
+    target: &Path,
+    db: &Database,
+) -> Result<(), MyError> {
+    let context = text_context(wallpaper, db);
+    let overlay = match &config.lockscreen.effects {
+        Some(profile) => Some(
+            config
+                .effects
+                .profiles
+                .get(profile)
+                .ok_or_else(|| {
+                    MyError::ImageError(format!("Unknown effects profile '{}'", profile))
+                })?
+                .as_slice(),
+        ),
+        None => None,
+    };
+    lockscreen::generate(
+        wallpaper,
+        output_size(config),
+        &config.lockscreen,
+        overlay.map(|effects| (effects, &context)),
+        target,
+    )
+}
+
+fn check_config(path: &Path, layers: Result<layers::Layers, ConfigError>) -> Result<(), MyError> {
+    let problems = match layers.and_then(|layers| layers.config()) {
+        Ok(config) => config.problems(),
+        Err(e) if !output::is_json() => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        Err(e) => vec![e.to_string()],
+    };
+    let check = output::ConfigCheck {
+        path: path.to_path_buf(),
+        valid: problems.is_empty(),
+        problems,
+    };
+    output::print(&check, |check| {
+        if check.valid {
+            println!("{}: OK", path.display());
+        } else {
+            let invalid = ConfigError::Invalid(check.problems.clone());
+            eprintln!("{}: {}", path.display(), invalid);
+        }
+    });
+    if !check.valid {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-fn archive(dir: PathBuf, archive_dir: PathBuf) -> std::io::Result<()> {
+fn show_config(layers: &layers::Layers, resolved: bool) {
+    let values = layers.resolved();
+    if output::is_json() {
+        let values: Vec<_> = values
+            .into_iter()
+            .map(|(key, value, layer)| output::ConfigValue { key, value, layer })
+            .collect();
+        return output::print(&output::ConfigValues { values }, |_| {});
+    }
+    let width = values
+        .iter()
+        .map(|(key, value, _)| key.len() + value.len() + 3)
+        .max()
+        .unwrap_or(0);
+    for (key, value, layer) in values {
+        let assignment = format!("{} = {}", key, value);
+        if resolved {
+            println!("{:<width$}  # {}", assignment, layer);
+        } else {
+            println!("{}", assignment);
+        }
+    }
+}
+
+fn archive(dir: PathBuf, archive_dir: PathBuf) -> std::io::Result<output::Archived> {
     let one_week_ago = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7);
 
     fs::create_dir_all(&archive_dir)?;
 
-    let mut archived_files = 0;
-
-    fs::read_dir(dir)?
+    let old_files: Vec<_> = fs::read_dir(dir)?
         .filter_map(|entry| {
             let entry = entry.ok()?;
             let metadata = entry.metadata().ok()?;
@@ -248,26 +1019,27 @@ fn archive(dir: PathBuf, archive_dir: PathBuf) -> std::io::Result<()> {
                 None
             }
         })
-        .for_each(|old_file_path| {
-            let archive_file_path = archive_dir.join(old_file_path.file_name().unwrap());
-            fs::rename(old_file_path, archive_file_path).unwrap();
-            archived_files += 1;
-        });
+        .collect();
+    let mut archived = Vec::new();
+    for old_file_path in old_files {
+        let archive_file_path = archive_dir.join(old_file_path.file_name().unwrap());
+        fs::rename(old_file_path, &archive_file_path)?;
+        archived.push(archive_file_path);
+    }
 
-    let total_files = fs::read_dir(&archive_dir)?
+    let total = fs::read_dir(&archive_dir)?
         .filter(|entry| {
             entry
                 .as_ref()
                 .ok()
                 .and_then(|e| e.metadata().ok())
-                .map_or(false, |m| m.is_file())
+                .is_some_and(|m| m.is_file())
         })
         .count();
 
-    println!(
-        "Archived {} files. Total files in archive: {}",
-        archived_files, total_files
-    );
-
-    Ok(())
+    Ok(output::Archived {
+        archive_dir,
+        archived,
+        total,
+    })
 }
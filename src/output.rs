@@ -0,0 +1,220 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How command results are printed, chosen with `--output`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// One JSON object per command on stdout, progress goes to the log instead
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Invalid format '{}', expected text or json", s)),
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// `println!` for progress messages. With `--output json` they are logged instead,
+/// so stdout only holds the result.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            log::info!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Print the result of a command as JSON, or with `text` for people.
+pub fn print<T: Serialize>(result: &T, text: impl FnOnce(&T)) {
+    if is_json() {
+        match serde_json::to_string(result) {
+            Ok(json) => println!("{}", json),
+            Err(e) => log::error!("Could not serialize the result: {}", e),
+        }
+    } else {
+        text(result)
+    }
+}
+
+/// A progress bar for `len` steps, hidden with `--output json`.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    if is_json() {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+            )
+            .expect("Failed to create progress bar style"),
+    );
+    pb
+}
+
+// The JSON results. Fields are only ever added, so scripts keep working.
+
+/// `refresh`
+#[derive(Serialize, Debug)]
+pub struct Refreshed {
+    /// The file or dynamic set that was picked
+    pub wallpaper: PathBuf,
+    /// The schedule rule that was active, if any
+    pub rule: Option<String>,
+}
+
+/// `current`
+#[derive(Serialize, Debug, Default)]
+pub struct Current {
+    pub path: PathBuf,
+    pub file_name: String,
+    /// Id at the source, for wallpapers with metadata
+    pub id: Option<String>,
+    pub source: Option<String>,
+    pub purity: Option<String>,
+    pub tags: Vec<String>,
+    pub dynamic: bool,
+    /// The image on screen, which differs from `path` for dynamic sets
+    pub frame: Option<PathBuf>,
+}
+
+/// `download`
+#[derive(Serialize, Debug, Default)]
+pub struct Downloaded {
+    pub source: String,
+    pub downloaded: Vec<PathBuf>,
+    pub failed: Vec<Failure>,
+    /// The wallpaper that was set with --apply
+    pub applied: Option<PathBuf>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Failure {
+    /// The id or URL, "search" when listing failed or "prune" when pruning did
+    pub input: String,
+    pub error: String,
+}
+
+/// `sync-collection`
+#[derive(Serialize, Debug, Default)]
+pub struct Synced {
+    /// `{username}/{collection_id}`
+    pub collection: String,
+    pub members: usize,
+    pub downloaded: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    /// Wallpapers that could not be downloaded, by id
+    pub failed: Vec<Failure>,
+}
+
+/// `archive`
+#[derive(Serialize, Debug, Default)]
+pub struct Archived {
+    pub archive_dir: PathBuf,
+    /// New paths of the files moved this time
+    pub archived: Vec<PathBuf>,
+    /// Files in the archive now
+    pub total: usize,
+}
+
+/// `dedupe`
+#[derive(Serialize, Debug)]
+pub struct Deduped {
+    pub dry_run: bool,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DuplicateGroup {
+    pub keep: PathBuf,
+    pub remove: Vec<PathBuf>,
+}
+
+/// `prune`
+#[derive(Serialize, Debug)]
+pub struct Pruned {
+    pub dry_run: bool,
+    pub removed: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+/// `lockscreen`
+#[derive(Serialize, Debug)]
+pub struct Lockscreen {
+    pub path: PathBuf,
+}
+
+/// `config check`
+#[derive(Serialize, Debug)]
+pub struct ConfigCheck {
+    pub path: PathBuf,
+    pub valid: bool,
+    pub problems: Vec<String>,
+}
+
+/// `config show`
+#[derive(Serialize, Debug)]
+pub struct ConfigValues {
+    pub values: Vec<ConfigValue>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConfigValue {
+    pub key: String,
+    /// The value in TOML syntax, secrets masked
+    pub value: String,
+    pub layer: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_schema() {
+        assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+        assert!("yaml".parse::<Format>().is_err());
+
+        let current = Current {
+            path: PathBuf::from("/w/a.jpg"),
+            file_name: "a.jpg".to_string(),
+            source: Some("folder".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&current).unwrap(),
+            serde_json::json!({
+                "path": "/w/a.jpg",
+                "file_name": "a.jpg",
+                "id": null,
+                "source": "folder",
+                "purity": null,
+                "tags": [],
+                "dynamic": false,
+                "frame": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::error::MyError;
+
+/// Perceptual hashes of an image, together with its size to pick the best of near-duplicates.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct ImageHash {
+    pub ahash: u64,
+    pub dhash: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageHash {
+    pub fn from_image(image: &DynamicImage) -> Self {
+        Self {
+            ahash: ahash(image),
+            dhash: dhash(image),
+            width: image.width(),
+            height: image.height(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, MyError> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    /// Number of differing bits, taking the worse of the two hashes.
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        let a = (self.ahash ^ other.ahash).count_ones();
+        let d = (self.dhash ^ other.dhash).count_ones();
+        a.max(d)
+    }
+
+    pub fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+/// Average hash: one bit per cell of an 8x8 thumbnail, set when brighter than the mean.
+fn ahash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(8, 8, FilterType::Triangle).to_luma8();
+    let mean = small.pixels().map(|p| p[0] as u32).sum::<u32>() / 64;
+    small
+        .pixels()
+        .enumerate()
+        .fold(0, |hash, (i, p)| hash | ((p[0] as u32 > mean) as u64) << i)
+}
+
+/// Difference hash: one bit per horizontal neighbour pair of a 9x8 thumbnail.
+fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash |= (brighter as u64) << (y * 8 + x);
+        }
+    }
+    hash
+}
+
+/// Group entries whose hashes are within `threshold` bits of each other.
+///
+/// Only groups with more than one member are returned, each sorted best first:
+/// highest resolution, then the first one seen.
+pub fn group_duplicates<K: Clone>(entries: &[(K, ImageHash)], threshold: u32) -> Vec<Vec<K>> {
+    // Union-find over the indices of `entries`.
+    let mut parent: Vec<usize> = (0..entries.len()).collect();
+    fn find(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..entries.len() {
+        for j in (i + 1)..entries.len() {
+            if entries[i].1.distance(&entries[j].1) <= threshold {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                parent[b] = a;
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..entries.len() {
+        let root = find(&mut parent, i);
+        groups.entry(root).or_default().push(i);
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+    groups
+        .into_iter()
+        .map(|mut group| {
+            group.sort_by_key(|&i| (std::cmp::Reverse(entries[i].1.pixels()), i));
+            group.into_iter().map(|i| entries[i].0.clone()).collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A smooth test image, `flip` inverts it.
+    pub(crate) fn pattern(width: u32, height: u32, flip: bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let fx = x as f32 / width as f32;
+            let fy = y as f32 / height as f32;
+            let v = ((fx * 6.0).sin() * (fy * 4.0).cos() * 127.0 + 128.0) as u8;
+            let v = if flip { 255 - v } else { v };
+            Rgb([v, v / 2, 255 - v])
+        }))
+    }
+
+    #[test]
+    fn test_near_duplicates() {
+        let original = ImageHash::from_image(&pattern(640, 400, false));
+        let resized = ImageHash::from_image(&pattern(1920, 1200, false));
+        let inverted = ImageHash::from_image(&pattern(640, 400, true));
+
+        assert!(
+            original.distance(&resized) <= 4,
+            "{:?} {:?}",
+            original,
+            resized
+        );
+        assert!(original.distance(&inverted) > 32);
+
+        let groups = group_duplicates(
+            &[("small", original), ("other", inverted), ("large", resized)],
+            6,
+        );
+        assert_eq!(groups, vec![vec!["large", "small"]]);
+    }
+}
@@ -0,0 +1,343 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbImage};
+use log::debug;
+use regex::Regex;
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::RenderMode;
+use crate::error::MyError;
+use crate::sources::folder::fnv1a;
+
+/// Files kept in each cache directory, the least recently used ones are removed first.
+pub(crate) const CACHED_FILES: usize = 32;
+
+/// Parse `2880x1800` into a width and height, neither of which may be zero.
+pub fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.trim().split_once('x')?;
+    let size = (width.trim().parse().ok()?, height.trim().parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+/// The command listing the outputs for `wallpaper_app`, and the pattern of the size in
+/// what it prints.
+fn size_query(wallpaper_app: &str) -> (&'static str, &'static [&'static str], &'static str) {
+    match wallpaper_app {
+        // eDP-1: 2880x1800, scale: 1, currently displaying: ...
+        "swww" => ("swww", &["query"], r"(\d+)x(\d+)"),
+        //     2880x1800 px, 60.001000 Hz (preferred, current)
+        "wayland" => (
+            "wlr-randr",
+            &[],
+            r"(\d+)x(\d+) px, [\d.]+ Hz \([^)]*current",
+        ),
+        // Screen 0: minimum 8 x 8, current 2880 x 1800, maximum 32767 x 32767
+        _ => ("xrandr", &["--current"], r"current (\d+) x (\d+)"),
+    }
+}
+
+/// Ask the wallpaper app (or the X11 or wlroots display) for the size of the first output.
+pub fn detect_output_size(wallpaper_app: &str) -> Option<(u32, u32)> {
+    let (program, args, pattern) = size_query(wallpaper_app);
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let caps = Regex::new(pattern).unwrap().captures(&stdout)?;
+    let size = (caps[1].parse().ok()?, caps[2].parse().ok()?);
+    debug!("Detected output size {:?} with {}", size, program);
+    Some(size)
+}
+
+/// Directory for renditions when `render.cache_dir` is not set.
+pub fn default_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("sinh-x/wallpaper")
+}
+
+/// Hash identifying the current content of `source` together with `extra` settings.
+///
+/// It stays the same between Rust releases, so an upgrade does not orphan the cache.
+pub(crate) fn source_key(source: &Path, extra: &str) -> Result<u32, MyError> {
+    let metadata = fs::metadata(source)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let key = format!(
+        "{}\0{}\0{}.{:09}\0{}",
+        fs::canonicalize(source)?.display(),
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos(),
+        extra
+    );
+    Ok(fnv1a(key.as_bytes()))
+}
+
+/// Cache file for `source` rendered at `size`; the key changes whenever the source
+/// file does, so stale renditions are never picked up.
+fn cache_path(
+    source: &Path,
+    size: (u32, u32),
+    mode: RenderMode,
+    cache_dir: &Path,
+) -> Result<PathBuf, MyError> {
+    let key = source_key(source, "")?;
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    Ok(cache_dir.join("renditions").join(format!(
+        "{}-{:08x}-{}x{}-{:?}.jpg",
+        stem, key, size.0, size.1, mode
+    )))
+}
+
+/// Render `source` to exactly `size` with `mode`, reusing a cached rendition when possible.
+pub fn render(
+    source: &Path,
+    size: (u32, u32),
+    mode: RenderMode,
+    cache_dir: &Path,
+) -> Result<PathBuf, MyError> {
+    let target = cache_path(source, size, mode, cache_dir)?;
+    if target.exists() {
+        debug!("Using cached rendition {:?}", target);
+        touch(&target);
+        return Ok(target);
+    }
+
+    let image = image::open(source)?;
+    let rendition = match mode {
+        RenderMode::Fill => image.resize_to_fill(size.0, size.1, FilterType::Lanczos3),
+        RenderMode::FitBlur => fit_blur(&image, size),
+        RenderMode::SmartCrop => smart_crop(&image, size),
+    };
+
+    let dir = target.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    save_jpeg(&rendition, &target)?;
+    debug!("Rendered {:?} to {:?}", source, target);
+    trim_cache(dir, CACHED_FILES);
+    Ok(target)
+}
+
+/// Mark a cached file as used, so `trim_cache` keeps it longer.
+pub(crate) fn touch(path: &Path) {
+    let touched = fs::File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        debug!("Could not touch {:?}: {}", path, e);
+    }
+}
+
+/// Remove all but the `keep` most recently used files in the cache directory `dir`.
+pub(crate) fn trim_cache(dir: &Path, keep: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            metadata
+                .is_file()
+                .then(|| (metadata.modified().ok(), entry.path()))
+        })
+        .collect();
+    files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    for (_, path) in files.into_iter().skip(keep) {
+        debug!("Removing {:?} from the cache", path);
+        if let Err(e) = fs::remove_file(&path) {
+            debug!("Could not remove {:?}: {}", path, e);
+        }
+    }
+}
+
+pub(crate) fn save_jpeg(image: &DynamicImage, path: &Path) -> Result<(), MyError> {
+    let file = BufWriter::new(fs::File::create(path)?);
+    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(file, 95);
+    image.to_rgb8().write_with_encoder(encoder)?;
+    Ok(())
+}
+
+/// Scale the whole image to fit, over a blurred and filled copy of itself.
+fn fit_blur(image: &DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+    // Blurring a downscaled copy is much cheaper and looks the same once scaled up.
+    let small = image
+        .resize_to_fill(
+            (width / 16).max(1),
+            (height / 16).max(1),
+            FilterType::Triangle,
+        )
+        .blur(2.0);
+    let mut background: RgbImage = small
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_rgb8();
+
+    let fitted = image.resize(width, height, FilterType::Lanczos3).to_rgb8();
+    let x = (width - fitted.width()) / 2;
+    let y = (height - fitted.height()) / 2;
+    imageops::overlay(&mut background, &fitted, x as i64, y as i64);
+    DynamicImage::ImageRgb8(background)
+}
+
+/// Crop to the output aspect ratio around the region with the most detail.
+fn smart_crop(image: &DynamicImage, size: (u32, u32)) -> DynamicImage {
+    let (x, y, crop_width, crop_height) = salient_window(image, size);
+    image
+        .crop_imm(x, y, crop_width, crop_height)
+        .resize_exact(size.0, size.1, FilterType::Lanczos3)
+}
+
+/// The largest window with the aspect ratio of `size`, slid along the free axis to
+/// where the gradient energy of the image is highest.
+fn salient_window(image: &DynamicImage, (width, height): (u32, u32)) -> (u32, u32, u32, u32) {
+    let (image_width, image_height) = image.dimensions();
+    let target_ratio = width as f64 / height as f64;
+    let (crop_width, crop_height) = if image_width as f64 / image_height as f64 > target_ratio {
+        (
+            (image_height as f64 * target_ratio).round() as u32,
+            image_height,
+        )
+    } else {
+        (
+            image_width,
+            (image_width as f64 / target_ratio).round() as u32,
+        )
+    };
+    let crop_width = crop_width.clamp(1, image_width);
+    let crop_height = crop_height.clamp(1, image_height);
+    if crop_width == image_width && crop_height == image_height {
+        return (0, 0, image_width, image_height);
+    }
+
+    // Work on a small grayscale copy, detail does not need full resolution.
+    let scale = 256.0 / image_width.max(image_height) as f64;
+    let scale = scale.min(1.0);
+    let gray: GrayImage = image
+        .resize_exact(
+            ((image_width as f64 * scale).round() as u32).max(2),
+            ((image_height as f64 * scale).round() as u32).max(2),
+            FilterType::Triangle,
+        )
+        .to_luma8();
+    let horizontal = crop_width < image_width;
+    let length = if horizontal {
+        gray.width()
+    } else {
+        gray.height()
+    };
+
+    // Energy per column (or row) along the axis the window slides on.
+    let mut energy = vec![0u64; length as usize];
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        let right = gray.get_pixel((x + 1).min(gray.width() - 1), y)[0];
+        let below = gray.get_pixel(x, (y + 1).min(gray.height() - 1))[0];
+        let gradient = pixel[0].abs_diff(right) as u64 + pixel[0].abs_diff(below) as u64;
+        energy[if horizontal { x } else { y } as usize] += gradient;
+    }
+
+    let window = if horizontal {
+        (crop_width as f64 * scale).round() as usize
+    } else {
+        (crop_height as f64 * scale).round() as usize
+    }
+    .clamp(1, length as usize);
+    let mut best = 0;
+    let mut sum: u64 = energy[..window].iter().sum();
+    let mut best_sum = sum;
+    for start in 1..=(length as usize - window) {
+        sum = sum + energy[start + window - 1] - energy[start - 1];
+        if sum > best_sum {
+            best_sum = sum;
+            best = start;
+        }
+    }
+
+    let offset = (best as f64 / scale).round() as u32;
+    if horizontal {
+        (
+            offset.min(image_width - crop_width),
+            0,
+            crop_width,
+            crop_height,
+        )
+    } else {
+        (
+            0,
+            offset.min(image_height - crop_height),
+            crop_width,
+            crop_height,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use tempfile::TempDir;
+
+    /// A flat portrait image with a checkerboard in its lower part.
+    fn portrait() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(400, 1000, |x, y| {
+            if y > 700 && (x / 10 + y / 10) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([40, 40, 40])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2880x1800"), Some((2880, 1800)));
+        assert_eq!(parse_size(" 1920 x 1080 "), Some((1920, 1080)));
+        assert_eq!(parse_size("1920"), None);
+        assert_eq!(parse_size("0x1080"), None);
+    }
+
+    #[test]
+    fn test_wlr_randr_size() {
+        let stdout = "eDP-1 \"Sharp Corporation 0x14D1 (eDP-1)\"\n  Modes:\n    \
+                      1920x1200 px, 59.950001 Hz\n    2880x1800 px, 60.001000 Hz (preferred, current)\n";
+        let caps = Regex::new(size_query("wayland").2)
+            .unwrap()
+            .captures(stdout)
+            .unwrap();
+        assert_eq!((&caps[1], &caps[2]), ("2880", "1800"));
+    }
+
+    #[test]
+    fn test_smart_crop_follows_detail() {
+        let (x, y, width, height) = salient_window(&portrait(), (400, 200));
+        assert_eq!((x, width, height), (0, 400, 200));
+        assert!(y >= 650, "window starts at {}", y);
+    }
+
+    #[test]
+    fn test_render_is_cached() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("portrait.png");
+        portrait().save(&source).unwrap();
+
+        for mode in [RenderMode::Fill, RenderMode::FitBlur, RenderMode::SmartCrop] {
+            let rendition = render(&source, (320, 200), mode, dir.path()).unwrap();
+            assert_eq!(image::image_dimensions(&rendition).unwrap(), (320, 200));
+            assert_eq!(
+                render(&source, (320, 200), mode, dir.path()).unwrap(),
+                rendition
+            );
+        }
+        let renditions = dir.path().join("renditions");
+        assert_eq!(fs::read_dir(&renditions).unwrap().count(), 3);
+
+        trim_cache(&renditions, 1);
+        assert_eq!(fs::read_dir(&renditions).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,171 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use log::debug;
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+use crate::config::{Schedule, ScheduleRule, TimeSpec, Tone};
+use crate::database::Database;
+use crate::library;
+
+/// Mean luma below which a wallpaper counts as dark, and above which as light.
+const DARK_BELOW: u8 = 96;
+const LIGHT_ABOVE: u8 = 150;
+
+/// Sunrise and sunset on `date` at the given location, `None` during polar day or night.
+///
+/// Uses the NOAA approximation, which is good to a couple of minutes.
+pub fn sun_times(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let gamma = 2.0 * PI / 365.0 * (date.ordinal0() as f64);
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = 90.833_f64.to_radians().cos() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let midnight = date.and_time(NaiveTime::MIN).and_utc();
+    let at = |minutes: f64| midnight + Duration::seconds((minutes * 60.0) as i64);
+    Some((
+        at(720.0 - 4.0 * (longitude + hour_angle) - equation_of_time),
+        at(720.0 - 4.0 * (longitude - hour_angle) - equation_of_time),
+    ))
+}
+
+/// Local time of day `spec` stands for on the day of `now`.
+fn resolve<Tz: TimeZone>(
+    spec: TimeSpec,
+    schedule: &Schedule,
+    now: &DateTime<Tz>,
+) -> Option<NaiveTime> {
+    let sun = || sun_times(now.date_naive(), schedule.latitude?, schedule.longitude?);
+    let (time, offset) = match spec {
+        TimeSpec::At(time) => return Some(time),
+        TimeSpec::Sunrise(offset) => (sun()?.0, offset),
+        TimeSpec::Sunset(offset) => (sun()?.1, offset),
+    };
+    Some(
+        (time + Duration::minutes(offset))
+            .with_timezone(&now.timezone())
+            .time(),
+    )
+}
+
+/// The first rule whose window contains `now`.
+pub fn active_rule<'a, Tz: TimeZone>(
+    schedule: &'a Schedule,
+    now: &DateTime<Tz>,
+) -> Option<&'a ScheduleRule> {
+    let time = now.time();
+    schedule.rules.iter().find(|rule| {
+        let (Some(from), Some(to)) = (
+            resolve(rule.from, schedule, now),
+            resolve(rule.to, schedule, now),
+        ) else {
+            return false;
+        };
+        if from <= to {
+            from <= time && time < to
+        } else {
+            time >= from || time < to
+        }
+    })
+}
+
+fn matches_tone(db: &Database, path: &std::path::Path, tone: Tone) -> bool {
+    match library::brightness_of(db, path) {
+        Ok(brightness) => match tone {
+            Tone::Dark => brightness < DARK_BELOW,
+            Tone::Light => brightness > LIGHT_ABOVE,
+        },
+        Err(e) => {
+            debug!("Could not measure {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Narrow `files` down to the ones `rule` asks for.
+///
+/// Tags are only known for wallpapers whose metadata was fetched, files without
+/// any never match a rule that lists tags.
+pub fn select(files: Vec<PathBuf>, rule: &ScheduleRule, db: &Database) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| {
+            if rule.tags.is_empty() {
+                return true;
+            }
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            db.get_tags(&file_name).is_ok_and(|tags| {
+                tags.iter()
+                    .any(|tag| rule.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+            })
+        })
+        .filter(|path| rule.tone.is_none_or(|tone| matches_tone(db, path, tone)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, Timelike};
+
+    fn rule(from: &str, to: &str, name: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: Some(name.to_string()),
+            from: TimeSpec::try_from(from.to_string()).unwrap(),
+            to: TimeSpec::try_from(to.to_string()).unwrap(),
+            dir: None,
+            tags: Vec::new(),
+            tone: None,
+        }
+    }
+
+    #[test]
+    fn test_sun_times_and_rules() {
+        // Berlin at the summer solstice: sunrise 02:43 UTC, sunset 19:33 UTC.
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let (sunrise, sunset) = sun_times(date, 52.52, 13.405).unwrap();
+        let minutes = |t: DateTime<Utc>| (t.hour() * 60 + t.minute()) as i64;
+        assert!((minutes(sunrise) - (2 * 60 + 43)).abs() <= 3);
+        assert!((minutes(sunset) - (19 * 60 + 33)).abs() <= 3);
+        assert!(sun_times(date, 80.0, 0.0).is_none());
+
+        let schedule = Schedule {
+            latitude: Some(52.52),
+            longitude: Some(13.405),
+            rules: vec![
+                rule("sunset", "sunrise+60", "night"),
+                rule("07:00", "12:00", "morning"),
+            ],
+        };
+        let berlin = FixedOffset::east_opt(2 * 3600).unwrap();
+        let at = |h, m| {
+            berlin
+                .from_local_datetime(&date.and_hms_opt(h, m, 0).unwrap())
+                .unwrap()
+        };
+        let active = |h, m| active_rule(&schedule, &at(h, m)).and_then(|r| r.name.clone());
+        assert_eq!(active(23, 0), Some("night".to_string()));
+        assert_eq!(active(5, 30), Some("night".to_string()));
+        assert_eq!(active(6, 0), None);
+        assert_eq!(active(9, 0), Some("morning".to_string()));
+        assert_eq!(active(18, 0), None);
+    }
+}
@@ -0,0 +1,294 @@
+use log::debug;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use std::path::Path;
+use std::process::Command;
+
+use crate::config::{Config, ConfigError, Feh, Swww};
+use crate::error::MyError;
+
+/// Put `wallpaper` on screen with the configured wallpaper app.
+///
+/// `transition` overrides the configured swww transition type on every output, and `fade`
+/// asks for a crossfade when nothing else is configured, e.g. between frames of a dynamic set.
+pub fn set(
+    config: &Config,
+    wallpaper: &Path,
+    transition: Option<&str>,
+    fade: bool,
+) -> Result<(), MyError> {
+    match config.general.wallpaper_app.as_str() {
+        "feh" => {
+            say!("Setting wallpaper using feh...");
+            let feh = config.feh.as_ref().ok_or_else(|| {
+                ConfigError::Invalid(vec![
+                    "The 'feh' section is missing in the config file".to_string()
+                ])
+            })?;
+            let monitors = if feh.outputs.is_empty() {
+                Vec::new()
+            } else {
+                xrandr_monitors().unwrap_or_else(|| {
+                    log::warn!("Could not list the outputs, ignoring [feh.outputs]");
+                    Vec::new()
+                })
+            };
+            run("feh", &feh_args(feh, wallpaper, &monitors))?;
+        }
+        "swww" => {
+            say!("Setting wallpaper using swww...");
+            let swww = config.swww.as_ref().ok_or_else(|| {
+                ConfigError::Invalid(vec![
+                    "The 'swww' section is missing in the config file".to_string()
+                ])
+            })?;
+            let random = swww
+                .random_transitions
+                .choose(&mut rand::thread_rng())
+                .map(String::as_str);
+            let base = random
+                .or_else(|| (fade && swww.transition.transition_type.is_none()).then_some("fade"));
+            let outputs = if swww.outputs.is_empty() {
+                Vec::new()
+            } else {
+                swww_outputs().unwrap_or_else(|| {
+                    log::warn!("Could not list the outputs, ignoring [swww.outputs]");
+                    Vec::new()
+                })
+            };
+            for args in swww_commands(swww, wallpaper, base, transition, &outputs) {
+                run("swww", &args)?;
+            }
+        }
+        #[cfg(feature = "wayland")]
+        "wayland" => {
+            say!("Setting wallpaper on the Wayland background layer...");
+            crate::wayland::show(wallpaper)?;
+        }
+        &_ => {
+            say!("Unknown wallpaper app");
+        }
+    }
+    Ok(())
+}
+
+/// Run a wallpaper app, reporting exactly the command that was executed.
+fn run(program: &str, args: &[String]) -> Result<(), MyError> {
+    let output = Command::new(program).args(args).output()?;
+    say!("Command: {} {}", program, args.join(" "));
+
+    if !output.status.success() {
+        return Err(MyError::Io(std::io::Error::other(format!(
+            "{} failed to set the wallpaper: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    say!("Wallpaper set successfully");
+    Ok(())
+}
+
+/// Arguments for feh, with one image per monitor (in Xinerama order) when some
+/// outputs have their own.
+fn feh_args(config: &Feh, wallpaper: &Path, monitors: &[String]) -> Vec<String> {
+    let mut args = vec![
+        config.mode.flag().to_string(),
+        "--image-bg".to_string(),
+        config.bg_color.clone(),
+    ];
+    if !config.fehbg {
+        args.push("--no-fehbg".to_string());
+    }
+    if config.span {
+        args.push("--no-xinerama".to_string());
+    }
+    let wallpaper = wallpaper.display().to_string();
+    if monitors.is_empty() {
+        args.push(wallpaper);
+    } else {
+        args.extend(monitors.iter().map(|monitor| {
+            config
+                .outputs
+                .get(monitor)
+                .and_then(|output| output.image.clone())
+                .unwrap_or_else(|| wallpaper.clone())
+        }));
+    }
+    args
+}
+
+/// Monitor names from `xrandr --listmonitors`, in the order feh assigns images.
+fn xrandr_monitors() -> Option<Vec<String>> {
+    let output = Command::new("xrandr").arg("--listmonitors").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    //  0: +*eDP-1 2880/301x1800/188+0+0  eDP-1
+    let re = Regex::new(r"(?m)^\s*\d+:\s+\+?\*?(\S+)").unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let monitors: Vec<_> = re
+        .captures_iter(&stdout)
+        .map(|caps| caps[1].to_string())
+        .collect();
+    debug!("xrandr monitors: {:?}", monitors);
+    (!monitors.is_empty()).then_some(monitors)
+}
+
+/// Arguments for each `swww img` call, one per output when some have their own options.
+///
+/// `base` replaces the configured transition type but not the ones set per output,
+/// `transition` replaces them all.
+fn swww_commands(
+    config: &Swww,
+    wallpaper: &Path,
+    base: Option<&str>,
+    transition: Option<&str>,
+    outputs: &[String],
+) -> Vec<Vec<String>> {
+    let mut defaults = config.transition.clone();
+    if let Some(base) = base {
+        defaults.transition_type = Some(base.to_string());
+    }
+    let runs = if outputs.is_empty() {
+        vec![(None, defaults)]
+    } else {
+        outputs
+            .iter()
+            .map(|output| {
+                let options = match config.outputs.get(output) {
+                    Some(overrides) => defaults.merged(overrides),
+                    None => defaults.clone(),
+                };
+                (Some(output), options)
+            })
+            .collect()
+    };
+
+    runs.into_iter()
+        .map(|(output, mut options)| {
+            if let Some(transition) = transition {
+                options.transition_type = Some(transition.to_string());
+            }
+            let mut args = vec!["img".to_string()];
+            if let Some(output) = output {
+                args.push("--outputs".to_string());
+                args.push(output.clone());
+            }
+            args.extend(options.args());
+            args.push(wallpaper.display().to_string());
+            args
+        })
+        .collect()
+}
+
+/// Output names from `swww query`.
+fn swww_outputs() -> Option<Vec<String>> {
+    let output = Command::new("swww").arg("query").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // : eDP-1: 2880x1800, scale: 1, currently displaying: ...
+    let re = Regex::new(r"(?m)^:?\s*([^\s:]+):\s*\d+x\d+").unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let outputs: Vec<_> = re
+        .captures_iter(&stdout)
+        .map(|caps| caps[1].to_string())
+        .collect();
+    debug!("swww outputs: {:?}", outputs);
+    (!outputs.is_empty()).then_some(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FehMode, FehOutput, SwwwTransition};
+
+    #[test]
+    fn test_feh_args() {
+        let mut config = Feh {
+            mode: FehMode::Fill,
+            bg_color: "#102030".to_string(),
+            fehbg: false,
+            ..Default::default()
+        };
+        let wallpaper = Path::new("/tmp/a.jpg");
+        assert_eq!(
+            feh_args(&config, wallpaper, &[]),
+            [
+                "--bg-fill",
+                "--image-bg",
+                "#102030",
+                "--no-fehbg",
+                "/tmp/a.jpg"
+            ]
+        );
+
+        config.outputs.insert(
+            "HDMI-1".to_string(),
+            FehOutput {
+                image: Some("/tmp/fixed.png".to_string()),
+            },
+        );
+        let monitors = ["HDMI-1".to_string(), "eDP-1".to_string()];
+        assert_eq!(
+            &feh_args(&config, wallpaper, &monitors)[4..],
+            ["/tmp/fixed.png", "/tmp/a.jpg"]
+        );
+    }
+
+    #[test]
+    fn test_swww_commands_per_output() {
+        let config = Swww {
+            transition: SwwwTransition {
+                transition_type: Some("wipe".to_string()),
+                transition_duration: Some(1.5),
+                transition_angle: Some(30.0),
+                resize: Some("crop".to_string()),
+                ..Default::default()
+            },
+            random_transitions: Vec::new(),
+            outputs: [(
+                "HDMI-A-1".to_string(),
+                SwwwTransition {
+                    transition_type: Some("grow".to_string()),
+                    transition_pos: Some("top-right".to_string()),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let wallpaper = Path::new("/tmp/a.jpg");
+
+        let single = swww_commands(&config, wallpaper, None, None, &[]);
+        assert_eq!(
+            single,
+            vec![vec![
+                "img",
+                "--transition-type",
+                "wipe",
+                "--transition-duration",
+                "1.5",
+                "--transition-angle",
+                "30",
+                "--resize",
+                "crop",
+                "/tmp/a.jpg"
+            ]]
+        );
+
+        let outputs = ["eDP-1".to_string(), "HDMI-A-1".to_string()];
+        let per_output = swww_commands(&config, wallpaper, None, None, &outputs);
+        assert_eq!(per_output.len(), 2);
+        assert_eq!(&per_output[0][..3], ["img", "--outputs", "eDP-1"]);
+        assert_eq!(per_output[1][4], "grow");
+        assert!(per_output[1].contains(&"top-right".to_string()));
+        assert!(per_output[1].contains(&"30".to_string()));
+
+        let random = swww_commands(&config, wallpaper, Some("outer"), None, &outputs);
+        assert_eq!(random[0][4], "outer");
+        assert_eq!(random[1][4], "grow");
+
+        let forced = swww_commands(&config, wallpaper, Some("outer"), Some("fade"), &outputs);
+        assert!(forced.iter().all(|args| args[4] == "fade"));
+    }
+}
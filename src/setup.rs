@@ -0,0 +1,209 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, ConfigError, Purity};
+use crate::error::MyError;
+
+/// The commented example config, filled in with the answers.
+const TEMPLATE: &str = include_str!("../default_config.toml");
+
+/// Answers for `setup`, taken from flags and asked for when missing.
+#[derive(Debug, Default)]
+pub struct Answers {
+    pub wallpaper_dir: Option<String>,
+    pub wallpaper_app: Option<String>,
+    pub purity: Option<String>,
+    pub api_key: Option<String>,
+    pub query: Option<String>,
+}
+
+/// Write a complete config to `config_path`, asking for anything not given unless
+/// `interactive` is off.
+pub fn run(
+    config_path: &Path,
+    answers: Answers,
+    interactive: bool,
+    force: bool,
+) -> Result<(), MyError> {
+    println!("Setting up...");
+
+    if config_path.exists() && !force {
+        let config = fs::read_to_string(config_path)?;
+        println!("Config: {}", config);
+        println!(
+            "{} already exists, run setup with --force to replace it",
+            config_path.display()
+        );
+        return Ok(());
+    }
+
+    let wayland = is_wayland();
+    let installed: Vec<_> = ["swww", "feh"]
+        .into_iter()
+        .filter(|program| find_program(program).is_some())
+        .collect();
+    println!(
+        "Session: {}, wallpaper apps found: {}",
+        if wayland { "Wayland" } else { "X11" },
+        if installed.is_empty() {
+            "none".to_string()
+        } else {
+            installed.join(", ")
+        }
+    );
+    let default_app = match (wayland, installed.as_slice()) {
+        (true, apps) if apps.contains(&"swww") => "swww",
+        (true, _) if cfg!(feature = "wayland") => "wayland",
+        (true, _) => "swww",
+        (false, _) => "feh",
+    };
+    let default_dir = dirs::picture_dir()
+        .unwrap_or_else(|| dirs::home_dir().unwrap_or_default().join("Pictures"))
+        .join("Wallpapers");
+
+    let ask = |question: &str, given: Option<String>, default: &str| match given {
+        Some(value) => Ok(value),
+        None if interactive => prompt(question, default),
+        None => Ok(default.to_string()),
+    };
+    let wallpaper_dir = ask(
+        "Wallpaper directory",
+        answers.wallpaper_dir,
+        &default_dir.display().to_string(),
+    )?;
+    let wallpaper_app = ask(
+        "Wallpaper app (swww, feh or wayland)",
+        answers.wallpaper_app,
+        default_app,
+    )?;
+    let purity = ask(
+        "Purity (sfw, sketchy, nsfw or several, e.g. sfw,sketchy)",
+        answers.purity,
+        "sfw",
+    )?;
+    let api_key = ask("Wallhaven API key", answers.api_key, "")?;
+    let query = ask("Search query", answers.query, "")?;
+
+    let purity = Purity::try_from(purity).map_err(|e| ConfigError::Invalid(vec![e]))?;
+    let contents = fill_template(
+        TEMPLATE,
+        &[
+            ("general", "wallpaper_dir", &wallpaper_dir),
+            ("general", "wallpaper_app", &wallpaper_app),
+            ("general", "purity", &purity.to_string()),
+            ("download", "api_key", &api_key),
+            ("download", "query", &query),
+        ],
+    );
+
+    for dir in [
+        PathBuf::from(&wallpaper_dir),
+        config::default_database_path(),
+    ] {
+        fs::create_dir_all(&dir)?;
+        println!("Created {}", dir.display());
+    }
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(config_path, contents)?;
+    println!("Wrote {}", config_path.display());
+
+    if let Ok(config) = config::Config::new(config_path) {
+        let problems = config.problems();
+        if !problems.is_empty() {
+            println!("{}", ConfigError::Invalid(problems));
+        }
+    }
+    Ok(())
+}
+
+fn prompt(question: &str, default: &str) -> Result<String, MyError> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+fn is_wayland() -> bool {
+    env::var_os("WAYLAND_DISPLAY").is_some()
+        || env::var("XDG_SESSION_TYPE").is_ok_and(|session| session == "wayland")
+}
+
+fn find_program(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Replace the value of each `(section, key, value)` in `template`, keeping the
+/// comments. Only uncommented keys are replaced.
+fn fill_template(template: &str, values: &[(&str, &str, &str)]) -> String {
+    let mut section = String::new();
+    let mut lines = Vec::new();
+    for line in template.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            section = trimmed.trim_matches(|c| c == '[' || c == ']').to_string();
+        }
+        let key = trimmed.split('=').next().unwrap_or_default().trim();
+        let replacement = values
+            .iter()
+            .find(|(s, k, _)| *s == section && *k == key && trimmed.contains('='));
+        match replacement {
+            Some((_, key, value)) => {
+                let value = toml::Value::String(value.to_string()).to_string();
+                let assignment = format!("{} = {}", key, value);
+                // Keep a trailing comment in its column
+                match line.find("  #") {
+                    Some(column) => {
+                        lines.push(format!("{:<column$}{}", assignment, &line[column..]))
+                    }
+                    None => lines.push(assignment),
+                }
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_template_makes_valid_config() {
+        let dir = TempDir::new().unwrap();
+        let wallpaper_dir = dir.path().display().to_string();
+        let contents = fill_template(
+            TEMPLATE,
+            &[
+                ("general", "wallpaper_dir", &wallpaper_dir),
+                ("general", "wallpaper_app", "feh"),
+                ("download", "api_key", "se\"cret"),
+                ("download", "query", "nature"),
+            ],
+        );
+        assert!(contents.contains("# transition_type = \"wipe\""));
+        assert!(contents.contains("wallpaper_app = \"feh\"   # swww, feh"));
+
+        let path = dir.path().join("config.toml");
+        fs::write(&path, contents).unwrap();
+        let config = Config::new(&path).unwrap();
+        assert_eq!(config.problems(), Vec::<String>::new());
+        assert_eq!(config.general.wallpaper_dir, wallpaper_dir);
+        assert_eq!(config.download.api_key, "se\"cret");
+        assert_eq!(config.download.query, "nature");
+    }
+}
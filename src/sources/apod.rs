@@ -0,0 +1,142 @@
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+
+use super::{file_type_from_url, get_json, url_with_params, SearchPage, WallpaperSource};
+use crate::config::ApodConfig;
+use crate::error::MyError;
+use crate::wallhaven::{Thumbs, Wallpaper};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.nasa.gov";
+
+#[derive(Deserialize, Debug)]
+struct Entry {
+    date: String,
+    title: String,
+    url: String,
+    hdurl: Option<String>,
+    media_type: String,
+}
+
+impl From<Entry> for Wallpaper {
+    fn from(entry: Entry) -> Self {
+        let path = entry.hdurl.unwrap_or_else(|| entry.url.clone());
+        let page = format!(
+            "https://apod.nasa.gov/apod/ap{}.html",
+            entry.date.replace('-', "").get(2..).unwrap_or_default()
+        );
+        Wallpaper {
+            id: entry.date,
+            url: page.clone(),
+            source: page,
+            purity: "sfw".to_string(),
+            category: entry.title,
+            file_type: file_type_from_url(&path),
+            path,
+            thumbs: Thumbs {
+                large: entry.url,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Apod {
+    api_key: String,
+    base_url: String,
+}
+
+impl Apod {
+    pub fn new(config: Option<&ApodConfig>) -> Self {
+        Self {
+            api_key: config
+                .and_then(|c| c.api_key.clone())
+                .unwrap_or_else(|| "DEMO_KEY".to_string()),
+            base_url: config
+                .and_then(|c| c.base_url.clone())
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Apod {
+    fn name(&self) -> &'static str {
+        "apod"
+    }
+
+    /// A random selection of past pictures, videos are skipped.
+    async fn search(&self, _page: u32) -> Result<SearchPage, MyError> {
+        let url = url_with_params(
+            &format!("{}/planetary/apod", self.base_url),
+            &[("api_key", &self.api_key), ("count", "20")],
+        )?;
+        let entries: Vec<Entry> = get_json(&url).await?;
+        let wallpapers: Vec<_> = entries
+            .into_iter()
+            .filter(|e| e.media_type == "image")
+            .map(Wallpaper::from)
+            .collect();
+        Ok(SearchPage {
+            total: wallpapers.len() as u32,
+            wallpapers,
+            last_page: 1,
+        })
+    }
+
+    /// Ids are dates, e.g. `2024-08-08`.
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let url = url_with_params(
+            &format!("{}/planetary/apod", self.base_url),
+            &[("api_key", &self.api_key), ("date", id)],
+        )?;
+        let entry: Entry = get_json(&url).await?;
+        if entry.media_type != "image" {
+            return Err(MyError::SourceError(format!(
+                "APOD of {} is a {}, not an image",
+                id, entry.media_type
+            )));
+        }
+        Ok(entry.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+
+    #[tokio::test]
+    async fn test_fetch_metadata() {
+        let mut server = Server::new_async().await;
+        let _image = server
+            .mock("GET", "/planetary/apod")
+            .match_query(Matcher::UrlEncoded("date".into(), "2024-08-08".into()))
+            .with_body(
+                r#"{"date": "2024-08-08", "title": "Perseids", "media_type": "image",
+                    "url": "https://apod.nasa.gov/apod/image/2408/perseids_1024.jpg",
+                    "hdurl": "https://apod.nasa.gov/apod/image/2408/perseids.png"}"#,
+            )
+            .create_async()
+            .await;
+        let _video = server
+            .mock("GET", "/planetary/apod")
+            .match_query(Matcher::UrlEncoded("date".into(), "2024-08-09".into()))
+            .with_body(
+                r#"{"date": "2024-08-09", "title": "Flyby", "media_type": "video",
+                    "url": "https://www.youtube.com/embed/xyz"}"#,
+            )
+            .create_async()
+            .await;
+
+        let apod = Apod::new(Some(&ApodConfig {
+            api_key: None,
+            base_url: Some(server.url()),
+        }));
+
+        let wallpaper = apod.fetch_metadata("2024-08-08").await.unwrap();
+        assert_eq!(wallpaper.url, "https://apod.nasa.gov/apod/ap240808.html");
+        assert_eq!(apod.file_name(&wallpaper), "apod-2024-08-08.png");
+        assert!(apod.fetch_metadata("2024-08-09").await.is_err());
+    }
+}
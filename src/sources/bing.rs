@@ -0,0 +1,138 @@
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+
+use super::{get_json, url_with_params, SearchPage, WallpaperSource};
+use crate::config::BingConfig;
+use crate::error::MyError;
+use crate::wallhaven::{Thumbs, Wallpaper};
+
+pub const DEFAULT_BASE_URL: &str = "https://www.bing.com";
+
+#[derive(Deserialize, Debug)]
+struct Archive {
+    images: Vec<Image>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Image {
+    startdate: String,
+    url: String,
+    urlbase: String,
+    copyright: String,
+    copyrightlink: String,
+}
+
+pub struct Bing {
+    market: String,
+    base_url: String,
+}
+
+impl Bing {
+    pub fn new(config: Option<&BingConfig>) -> Self {
+        Self {
+            market: config
+                .and_then(|c| c.market.clone())
+                .unwrap_or_else(|| "en-US".to_string()),
+            base_url: config
+                .and_then(|c| c.base_url.clone())
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        }
+    }
+
+    fn to_wallpaper(&self, image: Image) -> Wallpaper {
+        Wallpaper {
+            id: image.startdate,
+            url: image.copyrightlink.clone(),
+            source: image.copyrightlink,
+            purity: "sfw".to_string(),
+            category: image.copyright,
+            file_type: "image/jpeg".to_string(),
+            // The largest rendition Bing serves, its size is not part of the archive
+            path: format!("{}{}_UHD.jpg", self.base_url, image.urlbase),
+            thumbs: Thumbs {
+                large: format!("{}{}", self.base_url, image.url),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Bing {
+    fn name(&self) -> &'static str {
+        "bing"
+    }
+
+    /// The archive only goes back eight days, so there is a single page.
+    async fn search(&self, _page: u32) -> Result<SearchPage, MyError> {
+        let url = url_with_params(
+            &format!("{}/HPImageArchive.aspx", self.base_url),
+            &[
+                ("format", "js"),
+                ("idx", "0"),
+                ("n", "8"),
+                ("mkt", &self.market),
+            ],
+        )?;
+        let archive: Archive = get_json(&url).await?;
+        let wallpapers: Vec<_> = archive
+            .images
+            .into_iter()
+            .map(|i| self.to_wallpaper(i))
+            .collect();
+        Ok(SearchPage {
+            total: wallpapers.len() as u32,
+            wallpapers,
+            last_page: 1,
+        })
+    }
+
+    /// Ids are the start dates, e.g. `20240808`.
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        self.search(1)
+            .await?
+            .wallpapers
+            .into_iter()
+            .find(|w| w.id == id)
+            .ok_or_else(|| {
+                MyError::SourceError(format!("Bing image of {} is not in the archive", id))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    #[tokio::test]
+    async fn test_search_and_fetch_metadata() {
+        let mut server = Server::new_async().await;
+        let _archive = server
+            .mock("GET", "/HPImageArchive.aspx")
+            .match_query(mockito::Matcher::UrlEncoded("mkt".into(), "de-DE".into()))
+            .with_body(
+                r#"{"images": [{"startdate": "20240808",
+                    "url": "/th?id=OHR.Lake_DE-DE123_1920x1080.jpg&rf=LaDigue_1920x1080.jpg",
+                    "urlbase": "/th?id=OHR.Lake_DE-DE123",
+                    "copyright": "Lake (c) Someone", "copyrightlink": "https://www.bing.com/search?q=lake"}]}"#,
+            )
+            .expect(2)
+            .create_async()
+            .await;
+
+        let bing = Bing::new(Some(&BingConfig {
+            market: Some("de-DE".to_string()),
+            base_url: Some(server.url()),
+        }));
+
+        let page = bing.search(1).await.unwrap();
+        assert_eq!(
+            page.wallpapers[0].path,
+            format!("{}/th?id=OHR.Lake_DE-DE123_UHD.jpg", server.url())
+        );
+        assert_eq!(bing.file_name(&page.wallpapers[0]), "bing-20240808.jpeg");
+        assert!(bing.fetch_metadata("20240801").await.is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use async_trait::async_trait;
+use feed_rs::model::Entry;
+
+use super::{client, file_type_from_url, SearchPage, WallpaperSource};
+use crate::config::FeedConfig;
+use crate::error::MyError;
+use crate::wallhaven::Wallpaper;
+
+/// Images attached to any RSS or Atom feed, as enclosures, MediaRSS content or links.
+pub struct Feed {
+    urls: Vec<String>,
+}
+
+/// FNV-1a, a stable short id for image URLs, which are too long for file names.
+fn url_id(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+fn is_image(url: &str, media_type: Option<&str>) -> bool {
+    match media_type {
+        Some(media_type) => media_type.starts_with("image/"),
+        None => {
+            let path = url
+                .split('?')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            [".jpg", ".jpeg", ".png", ".webp"]
+                .iter()
+                .any(|ext| path.ends_with(ext))
+        }
+    }
+}
+
+fn entry_images(feed_url: &str, entry: &Entry) -> Vec<Wallpaper> {
+    let mut images = Vec::new();
+    for content in entry.media.iter().flat_map(|m| &m.content) {
+        let Some(url) = &content.url else { continue };
+        let media_type = content.content_type.as_ref().map(|t| t.to_string());
+        if is_image(url.as_str(), media_type.as_deref()) {
+            images.push((url.to_string(), content.width, content.height));
+        }
+    }
+    for link in &entry.links {
+        if is_image(&link.href, link.media_type.as_deref()) {
+            images.push((link.href.clone(), None, None));
+        }
+    }
+
+    let mut wallpapers: Vec<Wallpaper> = Vec::new();
+    for (url, width, height) in images {
+        if wallpapers.iter().any(|w| w.path == url) {
+            continue;
+        }
+        let page = entry
+            .links
+            .iter()
+            .find(|l| l.rel.as_deref().unwrap_or("alternate") == "alternate")
+            .map_or_else(|| feed_url.to_string(), |l| l.href.clone());
+        let (width, height) = (width.unwrap_or(0), height.unwrap_or(0));
+        wallpapers.push(Wallpaper {
+            id: url_id(&url),
+            url: page,
+            source: feed_url.to_string(),
+            purity: "sfw".to_string(),
+            category: entry
+                .title
+                .as_ref()
+                .map(|t| t.content.clone())
+                .unwrap_or_default(),
+            dimension_x: width,
+            dimension_y: height,
+            resolution: if width > 0 && height > 0 {
+                format!("{}x{}", width, height)
+            } else {
+                String::new()
+            },
+            file_type: file_type_from_url(&url),
+            created_at: entry
+                .published
+                .or(entry.updated)
+                .map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default(),
+            path: url,
+            ..Default::default()
+        });
+    }
+    wallpapers
+}
+
+impl Feed {
+    pub fn new(config: &FeedConfig) -> Self {
+        Self {
+            urls: config.urls.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Feed {
+    fn name(&self) -> &'static str {
+        "feed"
+    }
+
+    /// All configured feeds are read at once, feeds have no paging.
+    async fn search(&self, _page: u32) -> Result<SearchPage, MyError> {
+        let mut wallpapers = Vec::new();
+        for url in &self.urls {
+            let body = client()
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            let feed = feed_rs::parser::parse(&body[..])
+                .map_err(|e| MyError::SourceError(format!("Failed to parse {}: {}", url, e)))?;
+            for entry in &feed.entries {
+                wallpapers.extend(entry_images(url, entry));
+            }
+        }
+        Ok(SearchPage {
+            total: wallpapers.len() as u32,
+            wallpapers,
+            last_page: 1,
+        })
+    }
+
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let id = self.parse_id(id).unwrap_or_default();
+        self.search(1)
+            .await?
+            .wallpapers
+            .into_iter()
+            .find(|w| w.id == id)
+            .ok_or_else(|| MyError::SourceError(format!("No image {} in the feeds", id)))
+    }
+
+    /// Images are addressed by their URL, which is turned into the id used in file names.
+    fn parse_id(&self, input: &str) -> Option<String> {
+        let input = input.trim();
+        if input.contains("://") {
+            Some(url_id(input))
+        } else {
+            (!input.is_empty()).then(|| input.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    #[tokio::test]
+    async fn test_search_rss_and_atom() {
+        let mut server = Server::new_async().await;
+        let _rss = server
+            .mock("GET", "/rss.xml")
+            .with_body(
+                r#"<?xml version="1.0"?>
+                <rss version="2.0"><channel><title>Photos</title>
+                  <item><title>Lake</title><link>https://example.com/lake</link>
+                    <enclosure url="https://example.com/lake.jpg" length="1" type="image/jpeg"/></item>
+                  <item><title>Podcast</title>
+                    <enclosure url="https://example.com/episode.mp3" length="1" type="audio/mpeg"/></item>
+                </channel></rss>"#,
+            )
+            .create_async()
+            .await;
+        let _atom = server
+            .mock("GET", "/atom.xml")
+            .with_body(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+                <feed xmlns="http://www.w3.org/2005/Atom"><title>Art</title><id>urn:art</id>
+                  <updated>2024-08-08T12:00:00Z</updated>
+                  <entry><title>Dune</title><id>urn:art:1</id><updated>2024-08-08T12:00:00Z</updated>
+                    <link rel="alternate" href="https://example.org/dune"/>
+                    <link rel="enclosure" type="image/png" href="https://example.org/dune.png"/></entry>
+                </feed>"#,
+            )
+            .create_async()
+            .await;
+
+        let feed = Feed::new(&FeedConfig {
+            urls: vec![
+                format!("{}/rss.xml", server.url()),
+                format!("{}/atom.xml", server.url()),
+            ],
+        });
+
+        let page = feed.search(1).await.unwrap();
+        let paths: Vec<_> = page.wallpapers.iter().map(|w| w.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "https://example.com/lake.jpg",
+                "https://example.org/dune.png"
+            ]
+        );
+        let dune = &page.wallpapers[1];
+        assert_eq!(dune.url, "https://example.org/dune");
+        assert_eq!(
+            feed.parse_id("https://example.org/dune.png").as_ref(),
+            Some(&dune.id)
+        );
+        assert_eq!(feed.file_name(dune), format!("feed-{}.png", dune.id));
+    }
+}
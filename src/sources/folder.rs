@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::{file_type_from_url, SearchPage, WallpaperSource};
+use crate::config::{FolderConfig, Purity};
+use crate::database::Database;
+use crate::error::MyError;
+use crate::library;
+use crate::wallhaven::Wallpaper;
+
+/// Imports images dropped into another directory, e.g. a synced Nextcloud folder.
+pub struct Folder {
+    path: PathBuf,
+    remove_after_import: bool,
+}
+
+/// File stems become ids, keep them usable in our `folder-<id>.<ext>` file names.
+///
+/// Stems that had to be changed get a hash of the original, so that `a-b` and `a_b`
+/// do not end up as the same wallpaper.
+fn folder_id(stem: &str) -> String {
+    let id: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if id == stem {
+        return id;
+    }
+    format!("{}_{:08x}", id, fnv1a(stem.as_bytes()))
+}
+
+/// FNV-1a, which unlike the std hasher stays the same between Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+impl Folder {
+    pub fn new(config: &FolderConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.path),
+            remove_after_import: config.remove_after_import,
+        }
+    }
+
+    /// The id is made of the file stem, its size and its modification time, so a different
+    /// image dropped in later under a known name is imported as well.
+    fn to_wallpaper(path: &Path) -> Option<Wallpaper> {
+        let file_name = path.file_name()?.to_str()?;
+        if !library::is_image(path) {
+            return None;
+        }
+        let metadata = fs::metadata(path).ok()?;
+        let size: u64 = metadata.len();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or_default();
+        let version = fnv1a(format!("{}:{}", size, modified).as_bytes());
+        Some(Wallpaper {
+            id: format!("{}_{:08x}", folder_id(path.file_stem()?.to_str()?), version),
+            url: path.display().to_string(),
+            source: path.display().to_string(),
+            purity: "sfw".to_string(),
+            // The Wallhaven field is 32 bits, the version above has the real size
+            file_size: u32::try_from(size).unwrap_or(u32::MAX),
+            file_type: file_type_from_url(file_name),
+            created_at: modified,
+            path: path.display().to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Import every image in the folder that is not in the library yet.
+    pub async fn import(
+        &self,
+        db: &Database,
+        download_location: &str,
+        dedupe: Option<u32>,
+    ) -> Result<Vec<PathBuf>, MyError> {
+        let waiting = self.search(1).await?.total as u64;
+        if waiting == 0 {
+            return Ok(Vec::new());
+        }
+        // Whatever is dropped into the folder is wanted, whichever purity is configured
+        super::download(self, db, download_location, waiting, Purity::ALL, dedupe).await
+    }
+
+    /// Call `on_change` when images are added to the folder, for as long as the returned
+    /// watcher lives.
+    pub fn watch(
+        &self,
+        on_change: impl Fn() + Send + 'static,
+    ) -> notify::Result<RecommendedWatcher> {
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if (event.kind.is_create() || event.kind.is_modify())
+                        && event.paths.iter().any(|path| library::is_image(path))
+                    {
+                        on_change();
+                    }
+                }
+            })?;
+        watcher.watch(&self.path, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Folder {
+    fn name(&self) -> &'static str {
+        "folder"
+    }
+
+    async fn search(&self, _page: u32) -> Result<SearchPage, MyError> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.path)?
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect();
+        paths.sort();
+        let wallpapers: Vec<_> = paths.iter().filter_map(|p| Self::to_wallpaper(p)).collect();
+        Ok(SearchPage {
+            total: wallpapers.len() as u32,
+            wallpapers,
+            last_page: 1,
+        })
+    }
+
+    /// Ids are file names or stems inside the watched folder, or the ids of `search`.
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let direct = self.path.join(id);
+        if let Some(wallpaper) = Self::to_wallpaper(&direct) {
+            return Ok(wallpaper);
+        }
+        self.search(1)
+            .await?
+            .wallpapers
+            .into_iter()
+            .find(|w| {
+                w.id == id || Path::new(&w.path).file_stem().and_then(|s| s.to_str()) == Some(id)
+            })
+            .ok_or_else(|| {
+                MyError::SourceError(format!("No image {} in {}", id, self.path.display()))
+            })
+    }
+
+    async fn download_image(&self, wallpaper: &Wallpaper, file_path: &Path) -> Result<(), MyError> {
+        fs::copy(&wallpaper.path, file_path)?;
+        Ok(())
+    }
+
+    /// Only remove the original once it is safely in the library.
+    async fn after_import(&self, wallpaper: &Wallpaper) -> Result<(), MyError> {
+        if self.remove_after_import {
+            fs::remove_file(&wallpaper.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::sources;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_import_goes_through_download() {
+        let dir = TempDir::new().unwrap();
+        let inbox = dir.path().join("inbox");
+        let wallpapers = dir.path().join("wallpapers");
+        fs::create_dir_all(&inbox).unwrap();
+        fs::create_dir_all(&wallpapers).unwrap();
+        fs::write(inbox.join("sunset beach.JPG"), b"jpeg").unwrap();
+        fs::write(inbox.join("sunset_beach.JPG"), b"other").unwrap();
+        fs::write(inbox.join("notes.txt"), b"text").unwrap();
+        let db = Database::new(&dir.path().join("db")).unwrap();
+
+        let folder = Folder::new(&FolderConfig {
+            path: inbox.display().to_string(),
+            remove_after_import: true,
+        });
+        let location = wallpapers.display().to_string();
+        let imported = sources::download(&folder, &db, &location, 10, Purity::ALL, None)
+            .await
+            .unwrap();
+
+        let contents: Vec<_> = imported.iter().map(|p| fs::read(p).unwrap()).collect();
+        assert_eq!(contents, [b"jpeg".to_vec(), b"other".to_vec()]);
+        assert!(!inbox.join("sunset beach.JPG").exists());
+        assert!(inbox.join("notes.txt").exists());
+        let file_name = imported[1].file_name().unwrap().to_str().unwrap();
+        assert!(file_name.starts_with("folder-sunset_beach_"));
+        assert_eq!(db.get_source(file_name).unwrap(), "folder");
+    }
+
+    #[tokio::test]
+    async fn test_new_image_under_a_known_name() {
+        let dir = TempDir::new().unwrap();
+        let inbox = dir.path().join("inbox");
+        fs::create_dir_all(&inbox).unwrap();
+        let db = Database::new(&dir.path().join("db")).unwrap();
+        let folder = Folder::new(&FolderConfig {
+            path: inbox.display().to_string(),
+            remove_after_import: false,
+        });
+        let location = dir.path().join("wallpapers").display().to_string();
+
+        fs::write(inbox.join("photo.jpg"), b"first").unwrap();
+        assert_eq!(folder.import(&db, &location, None).await.unwrap().len(), 1);
+        assert!(folder
+            .import(&db, &location, None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fs::write(inbox.join("photo.jpg"), b"second photo").unwrap();
+        let imported = folder.import(&db, &location, None).await.unwrap();
+        assert_eq!(fs::read(&imported[0]).unwrap(), b"second photo");
+    }
+}
@@ -0,0 +1,365 @@
+use async_trait::async_trait;
+use log::debug;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Purity, PurityLevel};
+use crate::database::Database;
+use crate::error::MyError;
+use crate::output;
+use crate::phash::ImageHash;
+use crate::wallhaven::Wallpaper;
+
+pub mod apod;
+pub mod bing;
+pub mod feed;
+pub mod folder;
+pub mod reddit;
+pub mod unsplash;
+
+const USER_AGENT: &str = concat!("sinh-x-wallpaper/", env!("CARGO_PKG_VERSION"));
+
+/// One page of search results from a source.
+pub struct SearchPage {
+    pub wallpapers: Vec<Wallpaper>,
+    pub last_page: u32,
+    pub total: u32,
+}
+
+#[async_trait]
+pub trait WallpaperSource: Send + Sync {
+    /// Short name of the source, recorded in the database and used as file name prefix.
+    fn name(&self) -> &'static str;
+
+    /// List candidate wallpapers, `page` starts at 1.
+    async fn search(&self, page: u32) -> Result<SearchPage, MyError>;
+
+    /// Look up a single wallpaper by its id on the source.
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError>;
+
+    /// Fetch the image of `wallpaper` into `file_path`.
+    async fn download_image(&self, wallpaper: &Wallpaper, file_path: &Path) -> Result<(), MyError> {
+        let image_bytes = client()
+            .get(&wallpaper.path)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        fs::write(file_path, image_bytes)?;
+        Ok(())
+    }
+
+    /// Tags of `wallpaper`, stored on download for schedule rules and the `{tags}` text.
+    async fn tags(&self, _wallpaper: &Wallpaper) -> Result<Vec<String>, MyError> {
+        Ok(Vec::new())
+    }
+
+    /// Called once the image of `wallpaper` was accepted into the library, which is not
+    /// the case for rejected near-duplicates.
+    async fn after_import(&self, _wallpaper: &Wallpaper) -> Result<(), MyError> {
+        Ok(())
+    }
+
+    /// Turn user input (an id or a link) into an id understood by `fetch_metadata`.
+    fn parse_id(&self, input: &str) -> Option<String> {
+        let input = input.trim();
+        (!input.is_empty()).then(|| input.to_string())
+    }
+
+    fn file_name(&self, wallpaper: &Wallpaper) -> String {
+        wallpaper.file_name_with_prefix(self.name())
+    }
+}
+
+pub(crate) fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+pub(crate) async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, MyError> {
+    debug!("URL: {}", url);
+    let response_text = client()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(serde_json::from_str(&response_text)?)
+}
+
+/// Append percent-encoded query parameters to `base`.
+pub(crate) fn url_with_params(base: &str, params: &[(&str, &str)]) -> Result<String, MyError> {
+    reqwest::Url::parse_with_params(base, params)
+        .map(String::from)
+        .map_err(|e| MyError::SourceError(format!("Invalid URL {}: {}", base, e)))
+}
+
+/// Guess the mime type of an image from the extension in its URL.
+pub(crate) fn file_type_from_url(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        _ => "image/jpeg",
+    }
+    .to_string()
+}
+
+/// Look for `file_name` in the download location and its direct sub directories.
+pub fn find_in_library(download_location: &Path, file_name: &str) -> Option<PathBuf> {
+    let mut candidates = vec![download_location.join(file_name)];
+    if let Ok(entries) = fs::read_dir(download_location) {
+        for entry in entries.filter_map(Result::ok) {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                candidates.push(entry.path().join(file_name));
+            }
+        }
+    }
+    candidates.into_iter().find(|p| p.exists())
+}
+
+/// Purity of a wallpaper from its metadata, unknown ratings count as NSFW.
+fn purity_level(wallpaper: &Wallpaper) -> PurityLevel {
+    PurityLevel::from_name(&wallpaper.purity).unwrap_or(PurityLevel::Nsfw)
+}
+
+/// Where a new wallpaper goes, see `PurityLevel::dir`.
+fn target_dir(download_location: &Path, wallpaper: &Wallpaper) -> PathBuf {
+    match purity_level(wallpaper).dir() {
+        Some(dir) => download_location.join(dir),
+        None => download_location.to_path_buf(),
+    }
+}
+
+/// Hash a freshly downloaded file and record the hash in the database.
+///
+/// With a `dedupe` threshold, a file which is a near-duplicate of an image of at least the
+/// same resolution is deleted again and the file name of that image is returned. Files that
+/// cannot be decoded are kept as they are.
+fn check_duplicate(
+    db: &Database,
+    file_name: &str,
+    file_path: &Path,
+    dedupe: Option<u32>,
+) -> Result<Option<String>, MyError> {
+    let hash = match ImageHash::from_file(file_path) {
+        Ok(hash) => hash,
+        Err(e) => {
+            debug!("Could not hash {:?}: {}", file_path, e);
+            return Ok(None);
+        }
+    };
+    if let Some(threshold) = dedupe {
+        let duplicate = db.load_hashes()?.into_iter().find(|(name, existing)| {
+            name != file_name
+                && existing.distance(&hash) <= threshold
+                && existing.pixels() >= hash.pixels()
+        });
+        if let Some((existing, _)) = duplicate {
+            say!("Skipping {}, near-duplicate of {}", file_name, existing);
+            fs::remove_file(file_path)?;
+            return Ok(Some(existing));
+        }
+    }
+    db.save_hash(file_name, &hash)?;
+    Ok(None)
+}
+
+/// Download up to `limit` wallpapers from `source` that are not in the library yet.
+///
+/// Wallpapers outside of `purity` are skipped, and `dedupe` is the hash distance below
+/// which new images are rejected as near-duplicates. Returns the paths of the new wallpapers.
+pub async fn download(
+    source: &dyn WallpaperSource,
+    db: &Database,
+    download_location: &str,
+    limit: u64,
+    purity: Purity,
+    dedupe: Option<u32>,
+) -> Result<Vec<PathBuf>, MyError> {
+    say!("Downloading wallpaper from {}...", source.name());
+
+    let download_location = Path::new(download_location);
+    fs::create_dir_all(download_location.join("nsfw"))?;
+
+    let pb = output::progress_bar(limit);
+
+    let mut page = 1;
+    let mut total_pages = 1;
+    let mut count = 0;
+    let mut downloaded = Vec::new();
+    let mut sfw = 0;
+    let mut nsfw = 0;
+    while count < limit {
+        let response = source.search(page).await?;
+        if page == 1 {
+            total_pages = response.last_page;
+            say!("Total wallpapers to searched: {}", response.total);
+            say!("Listing pages: {}", response.last_page);
+            pb.tick(); // Redraw the progress bar immediately
+        }
+        for wallpaper in response.wallpapers {
+            if !purity.contains(purity_level(&wallpaper)) {
+                debug!("Skipping {}, purity {}", wallpaper.id, wallpaper.purity);
+                continue;
+            }
+            let file_name = source.file_name(&wallpaper);
+
+            if db.get_wallpaper_details(&file_name).is_ok() {
+                debug!("Wallpaper already exists in the database");
+                continue;
+            }
+            if find_in_library(download_location, &file_name).is_some() {
+                let _ = db.save_to_db(&file_name, &wallpaper);
+                let _ = db.save_source(&file_name, source.name());
+                continue;
+            }
+
+            let file_path = target_dir(download_location, &wallpaper).join(&file_name);
+
+            source.download_image(&wallpaper, &file_path).await?;
+            // Only now, a failed download is tried again next time
+            let _ = db.save_to_db(&file_name, &wallpaper);
+            let _ = db.save_source(&file_name, source.name());
+            if check_duplicate(db, &file_name, &file_path, dedupe)?.is_some() {
+                continue;
+            }
+            source.after_import(&wallpaper).await?;
+            match source.tags(&wallpaper).await {
+                Ok(tags) if !tags.is_empty() => db.save_tags(&file_name, &tags)?,
+                Ok(_) => {}
+                Err(e) => log::warn!("Could not fetch the tags of {}: {}", wallpaper.id, e),
+            }
+            if purity_level(&wallpaper) != PurityLevel::Sfw {
+                nsfw += 1;
+            } else {
+                sfw += 1;
+            }
+            count += 1;
+            pb.inc(1);
+
+            debug!("Saved wallpaper to: {:?}", file_path);
+            downloaded.push(file_path);
+            debug!("Current count: {}", count);
+
+            if count >= limit {
+                break;
+            }
+        }
+
+        page += 1;
+        if page > response.last_page {
+            break;
+        }
+    }
+
+    say!(
+        "Sfw: {} --- Nsfw {} --- reached: {}/{}",
+        sfw,
+        nsfw,
+        page,
+        total_pages
+    );
+    log::info!("Downloaded {} wallpapers from {}", count, source.name());
+    Ok(downloaded)
+}
+
+/// Fetch a single wallpaper by id and store it like `download` does.
+///
+/// Returns the local path of the wallpaper, which may already have existed in the library.
+pub async fn download_one(
+    source: &dyn WallpaperSource,
+    db: &Database,
+    download_location: &str,
+    id: &str,
+    dedupe: Option<u32>,
+) -> Result<PathBuf, MyError> {
+    let wallpaper = source.fetch_metadata(id).await?;
+
+    let file_name = source.file_name(&wallpaper);
+    let save = || -> Result<(), MyError> {
+        db.save_to_db(&file_name, &wallpaper)?;
+        db.save_source(&file_name, source.name())?;
+        Ok(())
+    };
+
+    let download_location = Path::new(download_location);
+    if let Some(existing) = find_in_library(download_location, &file_name) {
+        save()?;
+        say!("Already downloaded: {}", existing.display());
+        return Ok(existing);
+    }
+
+    let file_path = target_dir(download_location, &wallpaper);
+    fs::create_dir_all(&file_path)?;
+    let file_path = file_path.join(&file_name);
+
+    source.download_image(&wallpaper, &file_path).await?;
+    save()?;
+    if let Some(existing) = check_duplicate(db, &file_name, &file_path, dedupe)? {
+        return find_in_library(download_location, &existing).ok_or_else(|| {
+            MyError::SourceError(format!("{} is not in the library anymore", existing))
+        });
+    }
+    source.after_import(&wallpaper).await?;
+    say!("Downloaded: {}", file_path.display());
+    Ok(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_type_from_url() {
+        assert_eq!(file_type_from_url("https://i.redd.it/abc.png"), "image/png");
+        assert_eq!(
+            file_type_from_url("https://example.com/a.JPG?w=1920#x"),
+            "image/jpeg"
+        );
+        assert_eq!(
+            file_type_from_url("https://images.unsplash.com/photo-1?ixid=abc"),
+            "image/jpeg"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_rejects_near_duplicates() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let inbox = dir.path().join("inbox");
+        let wallpapers = dir.path().join("wallpapers");
+        fs::create_dir_all(&inbox).unwrap();
+        fs::create_dir_all(&wallpapers).unwrap();
+        for (name, width, height) in [("a_large", 1280, 800), ("b_small", 640, 400)] {
+            crate::phash::tests::pattern(width, height, false)
+                .save(inbox.join(format!("{}.png", name)))
+                .unwrap();
+        }
+        let db = Database::new(&dir.path().join("db")).unwrap();
+        let folder = folder::Folder::new(&crate::config::FolderConfig {
+            path: inbox.display().to_string(),
+            remove_after_import: true,
+        });
+
+        let location = wallpapers.display().to_string();
+        let imported = download(&folder, &db, &location, 10, Purity::ALL, Some(6))
+            .await
+            .unwrap();
+
+        assert_eq!(imported.len(), 1);
+        let file_name = imported[0].file_name().unwrap().to_str().unwrap();
+        assert!(file_name.starts_with("folder-a_large_"));
+        assert!(db.get_hash(file_name).is_ok());
+        assert_eq!(db.load_hashes().unwrap().len(), 1);
+        // Only the imported original is removed, the rejected one is not lost
+        assert!(!inbox.join("a_large.png").exists());
+        assert!(inbox.join("b_small.png").exists());
+    }
+}
@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+
+use super::{file_type_from_url, get_json, url_with_params, SearchPage, WallpaperSource};
+use crate::config::RedditConfig;
+use crate::error::MyError;
+use crate::wallhaven::{Thumbs, Wallpaper};
+
+pub const DEFAULT_BASE_URL: &str = "https://www.reddit.com";
+
+#[derive(Deserialize, Debug)]
+struct Listing {
+    data: ListingData,
+}
+
+#[derive(Deserialize, Debug)]
+struct ListingData {
+    children: Vec<Child>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Child {
+    data: Post,
+}
+
+#[derive(Deserialize, Debug)]
+struct Post {
+    id: String,
+    subreddit: String,
+    permalink: String,
+    url: String,
+    #[serde(default)]
+    over_18: bool,
+    #[serde(default)]
+    ups: u32,
+    #[serde(default)]
+    created_utc: f64,
+    preview: Option<Preview>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Preview {
+    images: Vec<PreviewImage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PreviewImage {
+    source: PreviewSource,
+}
+
+#[derive(Deserialize, Debug)]
+struct PreviewSource {
+    url: String,
+    width: u32,
+    height: u32,
+}
+
+impl Post {
+    /// Only direct links to images are usable, galleries and videos are skipped.
+    fn is_image(&self) -> bool {
+        let path = self
+            .url
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        [".jpg", ".jpeg", ".png", ".webp"]
+            .iter()
+            .any(|ext| path.ends_with(ext))
+    }
+}
+
+impl From<Post> for Wallpaper {
+    fn from(post: Post) -> Self {
+        let source = post
+            .preview
+            .and_then(|p| p.images.into_iter().next())
+            .map(|i| i.source);
+        let (width, height) = source.as_ref().map_or((0, 0), |s| (s.width, s.height));
+        let permalink = format!("{}{}", DEFAULT_BASE_URL, post.permalink);
+        Wallpaper {
+            id: post.id,
+            url: permalink.clone(),
+            favorites: post.ups,
+            source: permalink,
+            purity: if post.over_18 { "nsfw" } else { "sfw" }.to_string(),
+            category: post.subreddit,
+            dimension_x: width,
+            dimension_y: height,
+            resolution: if width > 0 {
+                format!("{}x{}", width, height)
+            } else {
+                String::new()
+            },
+            file_type: file_type_from_url(&post.url),
+            created_at: (post.created_utc as i64).to_string(),
+            thumbs: Thumbs {
+                large: source.map(|s| s.url).unwrap_or_default(),
+                original: post.url.clone(),
+                small: String::new(),
+            },
+            path: post.url,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Reddit {
+    subreddits: Vec<String>,
+    sort: String,
+    time: String,
+    base_url: String,
+}
+
+impl Reddit {
+    pub fn new(config: &RedditConfig) -> Self {
+        Self {
+            subreddits: config.subreddits.clone(),
+            sort: config.sort.clone().unwrap_or_else(|| "top".to_string()),
+            time: config.time.clone().unwrap_or_else(|| "week".to_string()),
+            base_url: config
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        }
+    }
+
+    async fn fetch_listing(&self, url: &str) -> Result<Vec<Wallpaper>, MyError> {
+        let listing: Listing = get_json(url).await?;
+        Ok(listing
+            .data
+            .children
+            .into_iter()
+            .map(|c| c.data)
+            .filter(Post::is_image)
+            .map(Wallpaper::from)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Reddit {
+    fn name(&self) -> &'static str {
+        "reddit"
+    }
+
+    /// Reddit pages with an `after` cursor, a single listing of 100 posts is plenty.
+    async fn search(&self, _page: u32) -> Result<SearchPage, MyError> {
+        let url = url_with_params(
+            &format!(
+                "{}/r/{}/{}.json",
+                self.base_url,
+                self.subreddits.join("+"),
+                self.sort
+            ),
+            &[("t", &self.time), ("limit", "100"), ("raw_json", "1")],
+        )?;
+        let wallpapers = self.fetch_listing(&url).await?;
+        Ok(SearchPage {
+            total: wallpapers.len() as u32,
+            wallpapers,
+            last_page: 1,
+        })
+    }
+
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let url = url_with_params(
+            &format!("{}/by_id/t3_{}.json", self.base_url, id),
+            &[("raw_json", "1")],
+        )?;
+        self.fetch_listing(&url)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| MyError::SourceError(format!("Reddit post {} is not an image", id)))
+    }
+
+    /// Accepts a post id or a link like `https://www.reddit.com/r/wallpaper/comments/<id>/...`.
+    fn parse_id(&self, input: &str) -> Option<String> {
+        let input = input.trim();
+        match input.split_once("/comments/") {
+            Some((_, rest)) => rest.split('/').next().map(String::from),
+            None => (!input.is_empty()).then(|| input.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    #[tokio::test]
+    async fn test_search_keeps_image_posts() {
+        let mut server = Server::new_async().await;
+        let _listing = server
+            .mock("GET", "/r/wallpapers+EarthPorn/top.json")
+            .match_query(mockito::Matcher::UrlEncoded("t".into(), "week".into()))
+            .with_body(
+                r#"{"data": {"after": "t3_x", "children": [
+                    {"data": {"id": "1abcde", "subreddit": "wallpapers", "over_18": false,
+                      "permalink": "/r/wallpapers/comments/1abcde/lake/", "ups": 120,
+                      "url": "https://i.redd.it/lake.png", "created_utc": 1723132800.0,
+                      "preview": {"images": [{"source": {"url": "https://preview.redd.it/lake.png",
+                        "width": 3840, "height": 2160}}]}}},
+                    {"data": {"id": "2fghij", "subreddit": "EarthPorn", "over_18": false,
+                      "permalink": "/r/EarthPorn/comments/2fghij/gallery/",
+                      "url": "https://www.reddit.com/gallery/2fghij"}}
+                ]}}"#,
+            )
+            .create_async()
+            .await;
+
+        let reddit = Reddit::new(&RedditConfig {
+            subreddits: vec!["wallpapers".to_string(), "EarthPorn".to_string()],
+            sort: None,
+            time: None,
+            base_url: Some(server.url()),
+        });
+
+        let page = reddit.search(1).await.unwrap();
+        assert_eq!(page.wallpapers.len(), 1);
+        let wallpaper = &page.wallpapers[0];
+        assert_eq!(wallpaper.category, "wallpapers");
+        assert_eq!(wallpaper.purity, "sfw");
+        assert_eq!(reddit.file_name(wallpaper), "reddit-1abcde-3840x2160.png");
+        assert_eq!(
+            reddit.parse_id("https://www.reddit.com/r/wallpapers/comments/1abcde/lake/"),
+            Some("1abcde".to_string())
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use async_trait::async_trait;
+use serde_derive::Deserialize;
+
+use super::{get_json, url_with_params, SearchPage, WallpaperSource};
+use crate::config::UnsplashConfig;
+use crate::error::MyError;
+use crate::wallhaven::{Thumbs, Wallpaper};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.unsplash.com";
+
+#[derive(Deserialize, Debug)]
+struct Photo {
+    id: String,
+    width: u32,
+    height: u32,
+    color: Option<String>,
+    created_at: String,
+    likes: u32,
+    urls: Urls,
+    links: Links,
+}
+
+#[derive(Deserialize, Debug)]
+struct Urls {
+    raw: String,
+    full: String,
+    regular: String,
+    small: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Links {
+    html: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResponse {
+    total: u32,
+    total_pages: u32,
+    results: Vec<Photo>,
+}
+
+impl From<Photo> for Wallpaper {
+    fn from(photo: Photo) -> Self {
+        Wallpaper {
+            id: photo.id,
+            url: photo.links.html.clone(),
+            favorites: photo.likes,
+            source: photo.links.html,
+            purity: "sfw".to_string(),
+            category: "general".to_string(),
+            dimension_x: photo.width,
+            dimension_y: photo.height,
+            resolution: format!("{}x{}", photo.width, photo.height),
+            ratio: format!("{:.2}", photo.width as f32 / photo.height.max(1) as f32),
+            file_type: "image/jpeg".to_string(),
+            created_at: photo.created_at,
+            colors: photo.color.into_iter().collect(),
+            path: photo.urls.full,
+            thumbs: Thumbs {
+                large: photo.urls.regular,
+                original: photo.urls.raw,
+                small: photo.urls.small,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Unsplash {
+    access_key: String,
+    query: String,
+    orientation: String,
+    base_url: String,
+}
+
+impl Unsplash {
+    pub fn new(config: &UnsplashConfig) -> Self {
+        Self {
+            access_key: config.access_key.clone(),
+            query: config
+                .query
+                .clone()
+                .unwrap_or_else(|| "wallpaper".to_string()),
+            orientation: config
+                .orientation
+                .clone()
+                .unwrap_or_else(|| "landscape".to_string()),
+            base_url: config
+                .base_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for Unsplash {
+    fn name(&self) -> &'static str {
+        "unsplash"
+    }
+
+    async fn search(&self, page: u32) -> Result<SearchPage, MyError> {
+        let url = url_with_params(
+            &format!("{}/search/photos", self.base_url),
+            &[
+                ("client_id", &self.access_key),
+                ("query", &self.query),
+                ("orientation", &self.orientation),
+                ("per_page", "30"),
+                ("page", &page.to_string()),
+            ],
+        )?;
+        let response: SearchResponse = get_json(&url).await?;
+        Ok(SearchPage {
+            wallpapers: response.results.into_iter().map(Wallpaper::from).collect(),
+            last_page: response.total_pages,
+            total: response.total,
+        })
+    }
+
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let url = url_with_params(
+            &format!("{}/photos/{}", self.base_url, id),
+            &[("client_id", &self.access_key)],
+        )?;
+        let photo: Photo = get_json(&url).await?;
+        Ok(photo.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{Matcher, Server};
+
+    #[tokio::test]
+    async fn test_search() {
+        let mut server = Server::new_async().await;
+        let _search = server
+            .mock("GET", "/search/photos")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("client_id".into(), "key".into()),
+                Matcher::UrlEncoded("query".into(), "mountains".into()),
+                Matcher::UrlEncoded("page".into(), "2".into()),
+            ]))
+            .with_body(
+                r##"{"total": 31, "total_pages": 2, "results": [{
+                    "id": "Dwu85P9SOIk", "width": 6000, "height": 4000, "color": "#6E633A",
+                    "created_at": "2016-05-03T11:00:28-04:00", "likes": 12,
+                    "urls": {"raw": "https://images.unsplash.com/photo-1?ixid=1",
+                             "full": "https://images.unsplash.com/photo-1?q=85",
+                             "regular": "https://images.unsplash.com/photo-1?w=1080",
+                             "small": "https://images.unsplash.com/photo-1?w=400"},
+                    "links": {"html": "https://unsplash.com/photos/Dwu85P9SOIk"}}]}"##,
+            )
+            .create_async()
+            .await;
+
+        let unsplash = Unsplash::new(&UnsplashConfig {
+            access_key: "key".to_string(),
+            query: Some("mountains".to_string()),
+            orientation: None,
+            base_url: Some(server.url()),
+        });
+
+        let page = unsplash.search(2).await.unwrap();
+        assert_eq!(page.last_page, 2);
+        assert_eq!(page.total, 31);
+        let wallpaper = &page.wallpapers[0];
+        assert_eq!(wallpaper.path, "https://images.unsplash.com/photo-1?q=85");
+        assert_eq!(wallpaper.colors, vec!["#6E633A"]);
+        assert_eq!(
+            unsplash.file_name(wallpaper),
+            "unsplash-Dwu85P9SOIk-6000x4000.jpeg"
+        );
+    }
+}
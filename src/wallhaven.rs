@@ -1,14 +1,21 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
 use log::debug;
+use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::from_str;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
-use crate::database::Database;
+use crate::config::Purity;
+use crate::database::{Database, DatabaseError};
 use crate::error::MyError;
+use crate::output::{Failure, Synced};
+use crate::sources::{SearchPage, WallpaperSource};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub const DEFAULT_BASE_URL: &str = "https://wallhaven.cc/api/v1";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct Wallpaper {
     pub id: String,
     pub url: String,
@@ -30,7 +37,24 @@ pub struct Wallpaper {
     pub thumbs: Thumbs,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+impl Wallpaper {
+    /// Local file name used for this wallpaper, e.g. `wallhaven-d6jzel-4999x3541.png`.
+    pub fn file_name(&self) -> String {
+        self.file_name_with_prefix("wallhaven")
+    }
+
+    /// Same naming scheme for other sources, the resolution is left out when it is unknown.
+    pub fn file_name_with_prefix(&self, prefix: &str) -> String {
+        let extension = self.file_type.split('/').next_back().unwrap();
+        if self.resolution.is_empty() {
+            format!("{}-{}.{}", prefix, self.id, extension)
+        } else {
+            format!("{}-{}-{}.{}", prefix, self.id, self.resolution, extension)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct Thumbs {
     pub large: String,
     pub original: String,
@@ -41,158 +65,396 @@ pub struct Thumbs {
 pub struct Meta {
     pub current_page: u32,
     pub last_page: u32,
+    /// The search endpoint returns this as a string, the collection endpoint as a number.
+    #[serde(deserialize_with = "string_or_number")]
     pub per_page: String,
     pub total: u32,
     pub query: Option<String>,
     pub seed: Option<String>,
 }
 
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match <serde_json::Value as serde::Deserialize>::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or a number, found {}",
+            other
+        ))),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Response {
     pub data: Vec<Wallpaper>,
     pub meta: Meta,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct DetailResponse {
+    pub data: Detail,
+}
+
+/// The detail endpoint returns the search fields plus tags and uploader.
+#[derive(Deserialize, Debug)]
+pub struct Detail {
+    #[serde(flatten)]
+    pub wallpaper: Wallpaper,
+    #[serde(default)]
+    pub tags: Vec<Tag>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Tag {
+    pub name: String,
+}
+
+/// Extract a wallpaper id from a bare id, a wallpaper page, a short link or a file name.
+///
+/// Accepts `d6jzel`, `https://wallhaven.cc/w/d6jzel`, `https://whvn.cc/d6jzel`,
+/// `https://w.wallhaven.cc/full/d6/wallhaven-d6jzel.png`,
+/// `https://th.wallhaven.cc/small/d6/d6jzel.jpg` and `wallhaven-d6jzel-4999x3541.png`.
+pub fn parse_id(input: &str) -> Option<String> {
+    lazy_static! {
+        static ref ID: Regex = Regex::new(concat!(
+            r"^(?:https?://)?(?:wallhaven\.cc/w/|whvn\.cc/)([a-z0-9]{6,})/?$",
+            r"|^(?:https?://)?(?:w|th)\.wallhaven\.cc/[a-z]+/[a-z0-9]{2}/(?:wallhaven-)?([a-z0-9]{6,})\.[a-z]+$",
+            r"|^wallhaven-([a-z0-9]{6,})(?:-\d+x\d+)?\.[a-z]+$",
+            r"|^([a-z0-9]{6,})$",
+        ))
+        .unwrap();
+    }
+    let caps = ID.captures(input.trim())?;
+    caps.iter()
+        .skip(1)
+        .flatten()
+        .next()
+        .map(|m| m.as_str().to_string())
+}
+
+/// Wallhaven's wallpaper categories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    General,
+    Anime,
+    People,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::General, Category::Anime, Category::People];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::General => "general",
+            Category::Anime => "anime",
+            Category::People => "people",
+        }
+    }
+}
+
+/// A set of categories, written as names ("general,anime") or as a bitmask ("110").
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Categories([bool; 3]);
+
+impl Categories {
+    pub fn of(categories: &[Category]) -> Self {
+        let mut set = [false; 3];
+        for category in categories {
+            set[*category as usize] = true;
+        }
+        Categories(set)
+    }
+
+    fn bitmask(self) -> String {
+        self.0
+            .iter()
+            .map(|&on| if on { '1' } else { '0' })
+            .collect()
+    }
+}
+
+impl Default for Categories {
+    fn default() -> Self {
+        Categories([true; 3])
+    }
+}
+
+impl TryFrom<String> for Categories {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let categories = if value.len() == 3 && value.chars().all(|c| c == '0' || c == '1') {
+            Categories([0, 1, 2].map(|i| &value[i..=i] == "1"))
+        } else {
+            let names = value
+                .split([',', '+', ' '])
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    Category::ALL
+                        .into_iter()
+                        .find(|category| category.name().eq_ignore_ascii_case(name))
+                        .ok_or_else(|| {
+                            format!(
+                                "Invalid category '{}', expected general, anime, people or a bitmask like 110",
+                                name
+                            )
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Categories::of(&names)
+        };
+        if !categories.0.contains(&true) {
+            return Err(format!("Categories '{}' do not allow any wallpaper", value));
+        }
+        Ok(categories)
+    }
+}
+
+/// Image formats a search can be limited to.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileType {
+    Png,
+    Jpg,
+}
+
+/// A Wallhaven search, built up step by step and rendered with `url`.
+///
+/// Keywords are matched loosely, tags given with `tag` must all be present and the
+/// ones given with `exclude_tag` must not be. A search for a tag by its id with
+/// `tag_id` stands alone, Wallhaven ignores every other term next to it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    keywords: String,
+    tags: Vec<String>,
+    exclude_tags: Vec<String>,
+    tag_id: Option<u64>,
+    uploader: Option<String>,
+    similar_to: Option<String>,
+    file_type: Option<FileType>,
+    categories: Categories,
+    purity: Purity,
+    at_least: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keywords(mut self, keywords: &str) -> Self {
+        self.keywords = keywords.trim().to_string();
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.trim().to_string());
+        self
+    }
+
+    pub fn exclude_tag(mut self, tag: &str) -> Self {
+        self.exclude_tags.push(tag.trim().to_string());
+        self
+    }
+
+    /// Exactly the wallpapers with the tag numbered `id`, replacing all other terms.
+    pub fn tag_id(mut self, id: u64) -> Self {
+        self.tag_id = Some(id);
+        self
+    }
+
+    /// Only wallpapers uploaded by `username`.
+    pub fn uploader(mut self, username: &str) -> Self {
+        self.uploader = Some(username.trim_start_matches('@').to_string());
+        self
+    }
+
+    /// Wallpapers similar to the one with `id`.
+    pub fn similar_to(mut self, id: &str) -> Self {
+        self.similar_to = Some(id.to_string());
+        self
+    }
+
+    pub fn file_type(mut self, file_type: FileType) -> Self {
+        self.file_type = Some(file_type);
+        self
+    }
+
+    pub fn categories(mut self, categories: Categories) -> Self {
+        self.categories = categories;
+        self
+    }
+
+    pub fn purity(mut self, purity: Purity) -> Self {
+        self.purity = purity;
+        self
+    }
+
+    /// Minimum resolution, e.g. "2880x1800".
+    pub fn at_least(mut self, resolution: &str) -> Self {
+        self.at_least = Some(resolution.to_string());
+        self
+    }
+
+    /// The `q` parameter in Wallhaven's search syntax, before encoding.
+    pub fn q(&self) -> String {
+        if let Some(id) = self.tag_id {
+            return format!("id:{}", id);
+        }
+        let mut terms = Vec::new();
+        if !self.keywords.is_empty() {
+            terms.push(self.keywords.clone());
+        }
+        terms.extend(self.tags.iter().map(|tag| format!("+{}", tag)));
+        terms.extend(self.exclude_tags.iter().map(|tag| format!("-{}", tag)));
+        if let Some(uploader) = &self.uploader {
+            terms.push(format!("@{}", uploader));
+        }
+        if let Some(id) = &self.similar_to {
+            terms.push(format!("like:{}", id));
+        }
+        if let Some(file_type) = self.file_type {
+            terms.push(match file_type {
+                FileType::Png => "type:png".to_string(),
+                FileType::Jpg => "type:jpg".to_string(),
+            });
+        }
+        terms.join(" ")
+    }
+
+    /// The search URL for `page` below `base_url`, without the API key.
+    pub fn url(&self, base_url: &str, page: u32) -> String {
+        let mut params = Vec::new();
+        let q = self.q();
+        if !q.is_empty() {
+            params.push(("q", q));
+        }
+        params.push(("categories", self.categories.bitmask()));
+        params.push(("purity", self.purity.bitmask()));
+        if let Some(at_least) = &self.at_least {
+            params.push(("atleast", at_least.clone()));
+        }
+        params.push(("page", page.to_string()));
+
+        let query: Vec<_> = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, encode(value)))
+            .collect();
+        format!("{}/search?{}", base_url, query.join("&"))
+    }
+}
+
+/// Percent-encode `value` for a query string, keeping only unreserved characters.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 pub struct WallHaven {
     api_key: String,
-    purity: String,
-    categories: String,
-    atleast: String,
-    query: String,
+    query: SearchQuery,
     download_location: String,
+    base_url: String,
     db: Database,
 }
 
 impl WallHaven {
-    pub fn new(
-        api_key: &str,
-        purity: &str,
-        categories: &str,
-        atleast: &str,
-        query: &str,
-        download_location: &str,
-        db: &Database,
-    ) -> Self {
+    pub fn new(api_key: &str, query: SearchQuery, download_location: &str, db: &Database) -> Self {
         Self {
             api_key: api_key.to_string(),
-            purity: purity.to_string(),
-            categories: categories.to_string(),
-            atleast: atleast.to_string(),
-            query: query.to_string(),
+            query,
             download_location: download_location.to_string(),
+            base_url: DEFAULT_BASE_URL.to_string(),
             db: db.clone(),
         }
     }
 
-    pub async fn download(&self) -> Result<(), MyError> {
-        println!("Downloading wallpaper...");
+    /// Point the client at another API host, e.g. a mirror or a local test server.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// GET `url` and return the body, with the API key as the `apikey` query parameter.
+    /// It is left out without a key so anonymous requests work, and never shows up in the
+    /// log or in errors.
+    async fn get(&self, url: &str) -> Result<String, MyError> {
+        debug!("URL: {}", url);
+        let mut request = reqwest::Client::new().get(url);
+        if !self.api_key.is_empty() {
+            request = request.query(&[("apikey", &self.api_key)]);
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url)?;
+        Ok(response.text().await.map_err(reqwest::Error::without_url)?)
+    }
+
+    /// Mirror the collection `{username}/{collection_id}` into `subdir` of the download location.
+    ///
+    /// Membership is recorded in the database so that, with `prune`, files which were removed
+    /// from the collection since the last pruning sync are deleted locally. Images that fail
+    /// to download are reported in the result and tried again next time.
+    pub async fn sync_collection(
+        &self,
+        username: &str,
+        collection_id: u64,
+        subdir: &str,
+        prune: bool,
+    ) -> Result<Synced, MyError> {
+        say!("Syncing collection {}/{}...", username, collection_id);
 
         let db = &self.db;
-        let api_key = &self.api_key;
-        let purity = &self.purity; // Replace with the actual purity value
-        let categories = &self.categories; // Replace with the actual category value
-        let atleast = &self.atleast;
-        let query = &self.query;
-        let download_location = &self.download_location;
-
-        let pb = ProgressBar::new(10);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-                )
-                .expect("Failed to create progress bar style"),
-        );
-        //
+        let collection_key = format!("{}/{}", username, collection_id);
+        let collection_dir = PathBuf::from(&self.download_location).join(subdir);
+        fs::create_dir_all(&collection_dir)?;
 
+        let mut synced = Synced {
+            collection: collection_key.clone(),
+            ..Default::default()
+        };
+        let mut members = Vec::new();
         let mut page = 1;
-        let mut total_pages = 1;
-        let mut count = 0;
-        let mut sfw = 0;
-        let mut nsfw = 0;
-        while count < 10 {
+        loop {
             let url = format!(
-            "https://wallhaven.cc/api/v1/search?apikey={}&purity={}&categories={}&page={}&atleast={}&q={}",
-            api_key, purity, categories, page, atleast, query
-        );
-            debug!("URL: {}", &url);
-            let response_text = reqwest::get(&url).await?.text().await?;
-
-            // Parse the JSON response into a Response instance
-            let response: Response = from_str(&response_text)?;
-            if page == 1 {
-                total_pages = response.meta.last_page;
-                println!("Total wallpapers to searched: {}", response.meta.total);
-                println!("Listing pages: {}", response.meta.last_page);
-                pb.tick(); // Redraw the progress bar immediately
-            }
+                "{}/collections/{}/{}?page={}",
+                self.base_url, username, collection_id, page
+            );
+            let response = self.fetch_page(&url).await?;
+
             for wallpaper in response.data {
-                let wallpaper_dir = download_location;
-                let folder_paths: Vec<_> = fs::read_dir(wallpaper_dir)
-                    .expect("Directory not found")
-                    .filter_map(|entry| {
-                        let entry = entry.ok()?;
-                        if entry.file_type().ok()?.is_dir() {
-                            Some(entry.path())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                let file_name = format!(
-                    "wallhaven-{}-{}.{}",
-                    wallpaper.id,
-                    wallpaper.resolution,
-                    wallpaper.file_type.split('/').last().unwrap()
-                );
-
-                match db.get_wallpaper_details(&file_name) {
-                    Ok(_) => {
-                        debug!("Wallpaper already exists in the database");
+                let file_name = wallpaper.file_name();
+                let file_path = collection_dir.join(&file_name);
+                // Still a member, so a failed download is not pruned as removed
+                members.push(file_name.clone());
+                if !file_path.exists() {
+                    if let Err(e) = self.download_image(&wallpaper, &file_path).await {
+                        log::error!("Failed to download {}: {}", wallpaper.id, e);
+                        synced.failed.push(Failure {
+                            input: wallpaper.id.clone(),
+                            error: e.to_string(),
+                        });
                         continue;
                     }
-                    Err(_) => {
-                        let _ = db.save_to_db(&file_name, &wallpaper);
-
-                        let mut file_exists = false;
-
-                        for folder_path in folder_paths {
-                            let full_path = Path::new(&folder_path).join(&file_name);
-                            if full_path.exists() {
-                                file_exists = true;
-                                break;
-                            }
-                        }
-
-                        if !file_exists {
-                            let mut file_path = PathBuf::from(download_location);
-                            if wallpaper.purity != "sfw" {
-                                nsfw += 1;
-                                file_path = file_path.join("nsfw");
-                            } else {
-                                sfw += 1;
-                            }
-
-                            file_path = file_path.join(&file_name);
-
-                            if !file_path.exists() {
-                                let image_bytes =
-                                    reqwest::get(&wallpaper.path).await?.bytes().await?;
-                                fs::write(&file_path, image_bytes)?;
-                                count += 1;
-                                pb.inc(1);
-
-                                debug!("Saved wallpaper to: {:?}", file_path);
-                                debug!("Current count: {}", count);
-
-                                if count >= 10 {
-                                    break;
-                                }
-                            }
-                        }
-                    }
+                    debug!("Saved wallpaper to: {:?}", file_path);
+                    synced.downloaded.push(file_path);
                 }
+                db.save_to_db(&file_name, &wallpaper)?;
+                db.save_source(&file_name, self.name())?;
             }
 
             page += 1;
@@ -201,10 +463,609 @@ impl WallHaven {
             }
         }
 
-        println!(
-            "Sfw: {} --- Nsfw {} --- reached: {}/{}",
-            sfw, nsfw, page, total_pages
+        let previous = match db.get_collection(&collection_key) {
+            Ok(previous) => previous,
+            Err(DatabaseError::KeyNotExist) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let gone = previous.into_iter().filter(|f| !members.contains(f));
+        if prune {
+            for file_name in gone {
+                let file_path = collection_dir.join(&file_name);
+                if file_path.exists() {
+                    fs::remove_file(&file_path)?;
+                    debug!("Removed wallpaper: {:?}", file_path);
+                    synced.removed.push(file_path);
+                }
+            }
+        } else {
+            // Remember them until a sync with `prune` removes them
+            let gone: Vec<_> = gone.collect();
+            members.extend(gone);
+        }
+        synced.members = members.len();
+
+        db.save_collection(&collection_key, &members)?;
+
+        say!(
+            "Collection {}: {} wallpapers --- downloaded {} --- removed {} --- failed {}",
+            collection_key,
+            synced.members,
+            synced.downloaded.len(),
+            synced.removed.len(),
+            synced.failed.len()
         );
-        Ok(())
+        Ok(synced)
+    }
+
+    async fn detail(&self, id: &str) -> Result<Detail, MyError> {
+        let url = format!("{}/w/{}", self.base_url, id);
+        let response_text = self.get(&url).await?;
+        Ok(from_str::<DetailResponse>(&response_text)?.data)
+    }
+
+    async fn fetch_page(&self, url: &str) -> Result<Response, MyError> {
+        let response_text = self.get(url).await?;
+
+        // Parse the JSON response into a Response instance
+        Ok(from_str(&response_text)?)
+    }
+}
+
+#[async_trait]
+impl WallpaperSource for WallHaven {
+    fn name(&self) -> &'static str {
+        "wallhaven"
+    }
+
+    async fn search(&self, page: u32) -> Result<SearchPage, MyError> {
+        let url = self.query.url(&self.base_url, page);
+        let response = self.fetch_page(&url).await?;
+        Ok(SearchPage {
+            wallpapers: response.data,
+            last_page: response.meta.last_page,
+            total: response.meta.total,
+        })
+    }
+
+    async fn fetch_metadata(&self, id: &str) -> Result<Wallpaper, MyError> {
+        let detail = self.detail(id).await?;
+        let tags: Vec<String> = detail.tags.into_iter().map(|t| t.name).collect();
+        self.db.save_tags(&detail.wallpaper.file_name(), &tags)?;
+        Ok(detail.wallpaper)
+    }
+
+    /// Search results come without tags, they are only on the detail page.
+    async fn tags(&self, wallpaper: &Wallpaper) -> Result<Vec<String>, MyError> {
+        let detail = self.detail(&wallpaper.id).await?;
+        Ok(detail.tags.into_iter().map(|t| t.name).collect())
+    }
+
+    fn parse_id(&self, input: &str) -> Option<String> {
+        parse_id(input)
+    }
+
+    fn file_name(&self, wallpaper: &Wallpaper) -> String {
+        wallpaper.file_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources;
+    use mockito::{Matcher, Server, ServerGuard};
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    const IMAGE_BYTES: &[u8] = b"not really a png";
+
+    fn fixture() -> Value {
+        let response_text = fs::read_to_string("data/wallhaven_test_response.json").unwrap();
+        serde_json::from_str(&response_text).unwrap()
+    }
+
+    /// Build a search page from a slice of the fixture, with image paths
+    /// rewritten to point at the mock server.
+    fn page(
+        server: &ServerGuard,
+        range: std::ops::Range<usize>,
+        page: u32,
+        last_page: u32,
+    ) -> String {
+        let mut response = fixture();
+        let data: Vec<Value> = response["data"].as_array().unwrap()[range]
+            .iter()
+            .cloned()
+            .map(|mut wallpaper| {
+                wallpaper["path"] = json!(format!(
+                    "{}/full/{}",
+                    server.url(),
+                    wallpaper["id"].as_str().unwrap()
+                ));
+                wallpaper
+            })
+            .collect();
+        response["meta"]["total"] = json!(data.len());
+        response["data"] = json!(data);
+        response["meta"]["current_page"] = json!(page);
+        response["meta"]["last_page"] = json!(last_page);
+        response.to_string()
+    }
+
+    fn wallpapers(range: std::ops::Range<usize>) -> Vec<Wallpaper> {
+        let response: Response = serde_json::from_value(fixture()).unwrap();
+        response.data[range].to_vec()
+    }
+
+    fn wallpaper_dir(dir: &TempDir) -> String {
+        dir.path().join("wallpapers").display().to_string()
+    }
+
+    fn setup(server: &ServerGuard) -> (TempDir, Database, WallHaven) {
+        let dir = TempDir::new().unwrap();
+        let db = Database::new(&dir.path().join("db")).unwrap();
+        let wallpaper_dir = dir.path().join("wallpapers");
+        fs::create_dir_all(&wallpaper_dir).unwrap();
+        let query = SearchQuery::new()
+            .keywords("nature")
+            .categories(Categories::of(&[Category::Anime]))
+            .purity(Purity::try_from("110".to_string()).unwrap())
+            .at_least("2880x1800");
+        let wallhaven = WallHaven::new("test-key", query, wallpaper_dir.to_str().unwrap(), &db)
+            .with_base_url(&server.url());
+        (dir, db, wallhaven)
+    }
+
+    fn search_mock(server: &mut ServerGuard, page: u32) -> mockito::Mock {
+        server
+            .mock("GET", "/search")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("apikey".into(), "test-key".into()),
+                Matcher::UrlEncoded("page".into(), page.to_string()),
+            ]))
+    }
+
+    #[tokio::test]
+    async fn test_download_routes_purity_and_saves_to_db() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..12, 1, 1);
+        let search = search_mock(&mut server, 1)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await;
+        let images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .expect(10)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None)
+            .await
+            .unwrap();
+
+        search.assert_async().await;
+        images.assert_async().await;
+
+        let wallpaper_dir = dir.path().join("wallpapers");
+        for wallpaper in wallpapers(0..10) {
+            let expected = if wallpaper.purity == "sfw" {
+                wallpaper_dir.join(wallpaper.file_name())
+            } else {
+                wallpaper_dir.join("nsfw").join(wallpaper.file_name())
+            };
+            assert_eq!(fs::read(&expected).unwrap(), IMAGE_BYTES, "{:?}", expected);
+            assert_eq!(
+                db.get_wallpaper_details(&wallpaper.file_name()).unwrap().id,
+                wallpaper.id
+            );
+        }
+        for wallpaper in wallpapers(10..12) {
+            assert!(db.get_wallpaper_details(&wallpaper.file_name()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_skips_other_purities() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..12, 1, 1);
+        let _search = search_mock(&mut server, 1)
+            .with_body(body)
+            .create_async()
+            .await;
+        let sfw: Vec<_> = wallpapers(0..12)
+            .into_iter()
+            .filter(|wallpaper| wallpaper.purity == "sfw")
+            .collect();
+        let images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .expect(sfw.len())
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        let downloaded = sources::download(
+            &wallhaven,
+            &db,
+            &wallpaper_dir(&dir),
+            10,
+            Purity::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        images.assert_async().await;
+        assert_eq!(downloaded.len(), sfw.len());
+        assert_eq!(db.load_from_db().unwrap().len(), sfw.len());
+    }
+
+    #[tokio::test]
+    async fn test_download_stores_tags() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..1, 1, 1);
+        let _search = search_mock(&mut server, 1)
+            .with_body(body)
+            .create_async()
+            .await;
+        let _images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .create_async()
+            .await;
+        let wallpaper = &wallpapers(0..1)[0];
+        let mut detail = fixture()["data"][0].clone();
+        detail["tags"] = json!([{ "name": "nature" }, { "name": "forest" }]);
+        let _detail = server
+            .mock("GET", format!("/w/{}", wallpaper.id).as_str())
+            .match_query(Matcher::Any)
+            .with_body(json!({ "data": detail }).to_string())
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db.get_tags(&wallpaper.file_name()).unwrap(),
+            ["nature", "forest"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_follows_pagination() {
+        let mut server = Server::new_async().await;
+        let first = page(&server, 0..6, 1, 2);
+        let second = page(&server, 6..12, 2, 2);
+        let page_one = search_mock(&mut server, 1)
+            .with_body(first)
+            .create_async()
+            .await;
+        let page_two = search_mock(&mut server, 2)
+            .with_body(second)
+            .create_async()
+            .await;
+        let images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .expect(10)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None)
+            .await
+            .unwrap();
+
+        page_one.assert_async().await;
+        page_two.assert_async().await;
+        images.assert_async().await;
+        assert_eq!(db.load_from_db().unwrap().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_download_skips_known_wallpapers() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..12, 1, 1);
+        let _search = search_mock(&mut server, 1)
+            .with_body(body)
+            .create_async()
+            .await;
+        let images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .expect(10)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        // The first one is already recorded, the second one is already on disk.
+        let known = wallpapers(0..2);
+        db.save_to_db(&known[0].file_name(), &known[0]).unwrap();
+        let nsfw_dir = dir.path().join("wallpapers").join("nsfw");
+        fs::create_dir_all(&nsfw_dir).unwrap();
+        fs::write(nsfw_dir.join(known[1].file_name()), b"existing").unwrap();
+
+        sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None)
+            .await
+            .unwrap();
+
+        images.assert_async().await;
+        assert!(!nsfw_dir.join(known[0].file_name()).exists());
+        assert_eq!(
+            fs::read(nsfw_dir.join(known[1].file_name())).unwrap(),
+            b"existing"
+        );
+        for wallpaper in wallpapers(2..12) {
+            assert!(db.get_wallpaper_details(&wallpaper.file_name()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_reports_search_errors() {
+        let mut server = Server::new_async().await;
+        let _search = search_mock(&mut server, 1)
+            .with_status(401)
+            .with_body(r#"{"error":"Unauthorized"}"#)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        match sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None).await
+        {
+            Err(MyError::Reqwest(e)) => {
+                assert_eq!(e.status(), Some(reqwest::StatusCode::UNAUTHORIZED))
+            }
+            other => panic!("Expected an HTTP error, got {:?}", other),
+        }
+        assert!(db.load_from_db().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_download_reports_image_errors() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..1, 1, 1);
+        let _search = search_mock(&mut server, 1)
+            .with_body(body)
+            .create_async()
+            .await;
+        let _images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_status(404)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        match sources::download(&wallhaven, &db, &wallpaper_dir(&dir), 10, Purity::ALL, None).await
+        {
+            Err(MyError::Reqwest(e)) => {
+                assert_eq!(e.status(), Some(reqwest::StatusCode::NOT_FOUND))
+            }
+            other => panic!("Expected an HTTP error, got {:?}", other),
+        }
+        let wallpaper = &wallpapers(0..1)[0];
+        assert!(!dir
+            .path()
+            .join("wallpapers/nsfw")
+            .join(wallpaper.file_name())
+            .exists());
+        // Not recorded, so the next download tries it again
+        assert!(db.get_wallpaper_details(&wallpaper.file_name()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_collection_mirrors_and_prunes() {
+        let mut server = Server::new_async().await;
+        let first = page(&server, 0..3, 1, 1);
+        let second = page(&server, 1..4, 1, 1);
+        let (dir, db, wallhaven) = setup(&server);
+        let collection_dir = dir.path().join("wallpapers/collections/42");
+
+        let sync = server
+            .mock("GET", "/collections/someone/42")
+            .match_query(Matcher::UrlEncoded("apikey".into(), "test-key".into()))
+            .with_body(first)
+            .create_async()
+            .await;
+        let images = server
+            .mock("GET", Matcher::Regex("^/full/".into()))
+            .with_body(IMAGE_BYTES)
+            .expect(4)
+            .create_async()
+            .await;
+
+        wallhaven
+            .sync_collection("someone", 42, "collections/42", true)
+            .await
+            .unwrap();
+        sync.remove_async().await;
+
+        let _sync = server
+            .mock("GET", "/collections/someone/42")
+            .match_query(Matcher::Any)
+            .with_body(second)
+            .create_async()
+            .await;
+
+        // Without prune the removed one stays, and is still known as removed later
+        let kept = wallhaven
+            .sync_collection("someone", 42, "collections/42", false)
+            .await
+            .unwrap();
+        assert!(kept.removed.is_empty());
+        let all = wallpapers(0..4);
+        assert!(collection_dir.join(all[0].file_name()).exists());
+
+        let pruned = wallhaven
+            .sync_collection("someone", 42, "collections/42", true)
+            .await
+            .unwrap();
+
+        images.assert_async().await;
+        assert_eq!(pruned.removed, [collection_dir.join(all[0].file_name())]);
+        assert!(!collection_dir.join(all[0].file_name()).exists());
+        for wallpaper in &all[1..] {
+            assert!(collection_dir.join(wallpaper.file_name()).exists());
+        }
+        assert_eq!(
+            db.get_collection("someone/42").unwrap(),
+            all[1..]
+                .iter()
+                .map(Wallpaper::file_name)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_collection_keeps_going_after_failed_images() {
+        let mut server = Server::new_async().await;
+        let body = page(&server, 0..3, 1, 1);
+        let all = wallpapers(0..3);
+        let _sync = server
+            .mock("GET", "/collections/someone/42")
+            .match_query(Matcher::Any)
+            .with_body(body)
+            .create_async()
+            .await;
+        let _broken = server
+            .mock("GET", format!("/full/{}", all[0].id).as_str())
+            .with_status(500)
+            .create_async()
+            .await;
+        let _images = server
+            .mock(
+                "GET",
+                Matcher::Regex(format!("^/full/({}|{})$", all[1].id, all[2].id)),
+            )
+            .with_body(IMAGE_BYTES)
+            .create_async()
+            .await;
+        let (_dir, db, wallhaven) = setup(&server);
+
+        let synced = wallhaven
+            .sync_collection("someone", 42, "collections/42", true)
+            .await
+            .unwrap();
+
+        assert_eq!(synced.downloaded.len(), 2);
+        assert_eq!(synced.failed.len(), 1);
+        assert_eq!(synced.failed[0].input, all[0].id);
+        assert!(db.get_wallpaper_details(&all[0].file_name()).is_err());
+        assert_eq!(db.get_collection("someone/42").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_id() {
+        for input in [
+            "d6jzel",
+            " d6jzel\n",
+            "https://wallhaven.cc/w/d6jzel",
+            "wallhaven.cc/w/d6jzel/",
+            "https://whvn.cc/d6jzel",
+            "https://w.wallhaven.cc/full/d6/wallhaven-d6jzel.png",
+            "https://th.wallhaven.cc/small/d6/d6jzel.jpg",
+            "wallhaven-d6jzel-4999x3541.png",
+        ] {
+            assert_eq!(parse_id(input).as_deref(), Some("d6jzel"), "{:?}", input);
+        }
+        for input in [
+            "https://example.com/d6jzel",
+            "https://example.com/full/d6/wallhaven-d6jzel.png",
+            "my-wallhaven-d6jzel.png",
+            "cat",
+            "",
+        ] {
+            assert_eq!(parse_id(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_search_query_urls() {
+        let base = "https://wallhaven.cc/api/v1";
+        assert_eq!(
+            SearchQuery::new().url(base, 1),
+            "https://wallhaven.cc/api/v1/search?categories=111&purity=100&page=1"
+        );
+
+        let query = SearchQuery::new()
+            .keywords("misty forest")
+            .tag("nature")
+            .tag("digital_art")
+            .exclude_tag("anime")
+            .uploader("@some_one")
+            .file_type(FileType::Png)
+            .categories("general,people".to_string().try_into().unwrap())
+            .purity(Purity::try_from("sfw,sketchy".to_string()).unwrap())
+            .at_least("2560x1440");
+        assert_eq!(
+            query.q(),
+            "misty forest +nature +digital_art -anime @some_one type:png"
+        );
+        assert_eq!(
+            query.url(base, 3),
+            "https://wallhaven.cc/api/v1/search?\
+             q=misty%20forest%20%2Bnature%20%2Bdigital_art%20-anime%20%40some_one%20type%3Apng\
+             &categories=101&purity=110&atleast=2560x1440&page=3"
+        );
+
+        let similar = SearchQuery::new().similar_to("d6jzel");
+        assert!(similar.url(base, 1).contains("q=like%3Ad6jzel&"));
+        let exact = SearchQuery::new().keywords("forest").tag_id(37);
+        assert_eq!(exact.q(), "id:37");
+        assert!(exact.url(base, 1).contains("q=id%3A37&"));
+        assert!(Categories::try_from("general,cats".to_string()).is_err());
+        assert!(Categories::try_from(String::new()).is_err());
+        assert!(Categories::try_from("000".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_by_id() {
+        let mut server = Server::new_async().await;
+        let mut wallpaper = fixture()["data"][0].clone();
+        wallpaper["path"] = json!(format!("{}/full/d6jzel", server.url()));
+        let detail = server
+            .mock("GET", "/w/d6jzel")
+            .match_query(Matcher::UrlEncoded("apikey".into(), "test-key".into()))
+            .with_body(json!({ "data": wallpaper }).to_string())
+            .expect(2)
+            .create_async()
+            .await;
+        let image = server
+            .mock("GET", "/full/d6jzel")
+            .with_body(IMAGE_BYTES)
+            .expect(1)
+            .create_async()
+            .await;
+        let (dir, db, wallhaven) = setup(&server);
+
+        let path = sources::download_one(&wallhaven, &db, &wallpaper_dir(&dir), "d6jzel", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            path,
+            dir.path()
+                .join("wallpapers/nsfw/wallhaven-d6jzel-4999x3541.png")
+        );
+        assert_eq!(fs::read(&path).unwrap(), IMAGE_BYTES);
+        assert!(db
+            .get_wallpaper_details("wallhaven-d6jzel-4999x3541.png")
+            .is_ok());
+
+        // A second request finds the file in the library and does not fetch it again.
+        assert_eq!(
+            sources::download_one(&wallhaven, &db, &wallpaper_dir(&dir), "d6jzel", None)
+                .await
+                .unwrap(),
+            path
+        );
+
+        detail.assert_async().await;
+        image.assert_async().await;
     }
 }
@@ -0,0 +1,378 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbaImage};
+use log::debug;
+use smithay_client_toolkit::reexports::calloop::channel::{self, Sender};
+use smithay_client_toolkit::reexports::calloop::EventLoop;
+use smithay_client_toolkit::reexports::calloop_wayland_source::WaylandSource;
+use smithay_client_toolkit::reexports::client::globals::registry_queue_init;
+use smithay_client_toolkit::reexports::client::protocol::{wl_output, wl_shm, wl_surface};
+use smithay_client_toolkit::reexports::client::{Connection, QueueHandle};
+use smithay_client_toolkit::{
+    compositor::{CompositorHandler, CompositorState},
+    delegate_compositor, delegate_layer, delegate_output, delegate_registry, delegate_shm,
+    output::{OutputHandler, OutputState},
+    registry::{ProvidesRegistryState, RegistryState},
+    registry_handlers,
+    shell::{
+        wlr_layer::{
+            Anchor, KeyboardInteractivity, Layer, LayerShell, LayerShellHandler, LayerSurface,
+            LayerSurfaceConfigure,
+        },
+        WaylandSurface,
+    },
+    shm::{
+        slot::{Buffer, SlotPool},
+        Shm, ShmHandler,
+    },
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::error::MyError;
+
+/// The running setter: images sent here replace the one on screen.
+static SETTER: Mutex<Option<(Sender<PathBuf>, JoinHandle<()>)>> = Mutex::new(None);
+
+fn error(message: impl std::fmt::Display) -> MyError {
+    MyError::ImageError(format!("Wayland: {}", message))
+}
+
+/// Show `wallpaper` on every output, connecting to the compositor on first use.
+///
+/// The image is drawn on a wlr-layer-shell background surface per output. Like with
+/// swaybg, the surfaces only live as long as the process, see `wait`.
+pub fn show(wallpaper: &Path) -> Result<(), MyError> {
+    let mut setter = SETTER.lock().unwrap();
+    if setter.is_none() {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            if let Err(e) = run(&ready_tx) {
+                let _ = ready_tx.send(Err(e));
+            }
+        });
+        let sender = ready_rx
+            .recv()
+            .map_err(|_| error("the setter thread stopped"))??;
+        *setter = Some((sender, handle));
+    }
+    let (sender, _) = setter.as_ref().unwrap();
+    sender
+        .send(wallpaper.to_path_buf())
+        .map_err(|_| error("the setter thread stopped"))
+}
+
+/// Keep the wallpaper on screen until the compositor or a newer instance ends it.
+pub fn wait() {
+    let setter = SETTER.lock().unwrap().take();
+    if let Some((sender, handle)) = setter {
+        say!("Keeping the wallpaper on screen, stop with Ctrl-C");
+        // Keep the channel open, a closed one would end the event loop.
+        let _ = handle.join();
+        drop(sender);
+    }
+}
+
+type Ready = mpsc::Sender<Result<Sender<PathBuf>, MyError>>;
+
+fn run(ready: &Ready) -> Result<(), MyError> {
+    let conn = Connection::connect_to_env().map_err(error)?;
+    let (globals, event_queue) = registry_queue_init(&conn).map_err(error)?;
+    let qh = event_queue.handle();
+
+    let mut event_loop: EventLoop<Background> = EventLoop::try_new().map_err(error)?;
+    let (sender, images) = channel::channel::<PathBuf>();
+    event_loop
+        .handle()
+        .insert_source(images, |event, _, state: &mut Background| match event {
+            channel::Event::Msg(path) => state.set_image(&path),
+            channel::Event::Closed => state.exit = true,
+        })
+        .map_err(|e| error(e.error))?;
+    WaylandSource::new(conn, event_queue)
+        .insert(event_loop.handle())
+        .map_err(|e| error(e.error))?;
+
+    let shm = Shm::bind(&globals, &qh).map_err(error)?;
+    let mut state = Background {
+        registry_state: RegistryState::new(&globals),
+        output_state: OutputState::new(&globals, &qh),
+        compositor: CompositorState::bind(&globals, &qh).map_err(error)?,
+        layer_shell: LayerShell::bind(&globals, &qh)
+            .map_err(|_| error("the compositor does not support wlr-layer-shell"))?,
+        pool: SlotPool::new(4096, &shm).map_err(error)?,
+        shm,
+        image: None,
+        surfaces: Vec::new(),
+        replaced_previous: false,
+        exit: false,
+    };
+    let _ = ready.send(Ok(sender));
+
+    while !state.exit {
+        event_loop.dispatch(None, &mut state).map_err(error)?;
+    }
+    Ok(())
+}
+
+struct OutputSurface {
+    output: wl_output::WlOutput,
+    layer: LayerSurface,
+    /// Logical size from the last configure, zero until then
+    size: (u32, u32),
+    buffer: Option<Buffer>,
+}
+
+struct Background {
+    registry_state: RegistryState,
+    output_state: OutputState,
+    compositor: CompositorState,
+    layer_shell: LayerShell,
+    shm: Shm,
+    pool: SlotPool,
+    image: Option<DynamicImage>,
+    surfaces: Vec<OutputSurface>,
+    replaced_previous: bool,
+    exit: bool,
+}
+
+impl Background {
+    fn set_image(&mut self, path: &Path) {
+        match image::open(path) {
+            Ok(image) => {
+                debug!("Showing {:?}", path);
+                self.image = Some(image);
+                for index in 0..self.surfaces.len() {
+                    self.draw(index);
+                }
+                if !self.replaced_previous {
+                    self.replaced_previous = true;
+                    replace_previous();
+                }
+            }
+            Err(e) => log::error!("Failed to open {}: {}", path.display(), e),
+        }
+    }
+
+    fn draw(&mut self, index: usize) {
+        let Some(image) = &self.image else {
+            return;
+        };
+        let surface = &mut self.surfaces[index];
+        if surface.size.0 == 0 || surface.size.1 == 0 {
+            return;
+        }
+        let scale = self
+            .output_state
+            .info(&surface.output)
+            .map_or(1, |info| info.scale_factor.max(1)) as u32;
+        let (width, height) = (surface.size.0 * scale, surface.size.1 * scale);
+        let pixels: RgbaImage = image
+            .resize_to_fill(width, height, FilterType::Lanczos3)
+            .to_rgba8();
+
+        let stride = width as i32 * 4;
+        let (buffer, canvas) = match self.pool.create_buffer(
+            width as i32,
+            height as i32,
+            stride,
+            wl_shm::Format::Xrgb8888,
+        ) {
+            Ok(created) => created,
+            Err(e) => {
+                log::error!("Failed to create a buffer: {}", e);
+                return;
+            }
+        };
+        // Xrgb8888 is little endian, so the bytes are blue, green, red, unused.
+        for (target, source) in canvas.chunks_exact_mut(4).zip(pixels.pixels()) {
+            let [r, g, b, _] = source.0;
+            target.copy_from_slice(&[b, g, r, 0xff]);
+        }
+
+        let wl_surface = surface.layer.wl_surface();
+        wl_surface.set_buffer_scale(scale as i32);
+        wl_surface.damage_buffer(0, 0, width as i32, height as i32);
+        if let Err(e) = buffer.attach_to(wl_surface) {
+            log::error!("Failed to attach the buffer: {:?}", e);
+            return;
+        }
+        surface.layer.commit();
+        surface.buffer = Some(buffer);
+    }
+}
+
+/// Stop the setter a previous `refresh` left running, now that this one is on screen.
+fn replace_previous() {
+    let pid_file = dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("sinh-x-wallpaper-wayland.pid");
+    if let Ok(pid) = fs::read_to_string(&pid_file) {
+        let pid = pid.trim();
+        if pid
+            .parse::<u32>()
+            .is_ok_and(|pid| pid != std::process::id() && is_setter(pid))
+        {
+            debug!("Stopping the previous setter {}", pid);
+            let _ = std::process::Command::new("kill").arg(pid).status();
+        }
+    }
+    if let Err(e) = fs::write(&pid_file, std::process::id().to_string()) {
+        debug!("Could not write {:?}: {}", pid_file, e);
+    }
+}
+
+/// Whether `pid` still runs this program, and not something that got the pid since.
+fn is_setter(pid: u32) -> bool {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid));
+    match (exe, std::env::current_exe()) {
+        (Ok(exe), Ok(current)) => exe == current,
+        _ => false,
+    }
+}
+
+impl CompositorHandler for Background {
+    fn scale_factor_changed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        surface: &wl_surface::WlSurface,
+        _new_factor: i32,
+    ) {
+        if let Some(index) = self
+            .surfaces
+            .iter()
+            .position(|s| s.layer.wl_surface() == surface)
+        {
+            self.draw(index);
+        }
+    }
+
+    fn transform_changed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _new_transform: wl_output::Transform,
+    ) {
+    }
+
+    fn frame(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _time: u32,
+    ) {
+    }
+
+    fn surface_enter(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _output: &wl_output::WlOutput,
+    ) {
+    }
+
+    fn surface_leave(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _output: &wl_output::WlOutput,
+    ) {
+    }
+}
+
+impl OutputHandler for Background {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        output: wl_output::WlOutput,
+    ) {
+        let surface = self.compositor.create_surface(qh);
+        let layer = self.layer_shell.create_layer_surface(
+            qh,
+            surface,
+            Layer::Background,
+            Some("wallpaper"),
+            Some(&output),
+        );
+        layer.set_anchor(Anchor::all());
+        layer.set_exclusive_zone(-1);
+        layer.set_keyboard_interactivity(KeyboardInteractivity::None);
+        layer.set_size(0, 0);
+        layer.commit();
+        self.surfaces.push(OutputSurface {
+            output,
+            layer,
+            size: (0, 0),
+            buffer: None,
+        });
+    }
+
+    fn update_output(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: wl_output::WlOutput,
+    ) {
+    }
+
+    fn output_destroyed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        output: wl_output::WlOutput,
+    ) {
+        self.surfaces.retain(|surface| surface.output != output);
+    }
+}
+
+impl LayerShellHandler for Background {
+    fn closed(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, layer: &LayerSurface) {
+        self.surfaces.retain(|surface| &surface.layer != layer);
+    }
+
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        layer: &LayerSurface,
+        configure: LayerSurfaceConfigure,
+        _serial: u32,
+    ) {
+        if let Some(index) = self.surfaces.iter().position(|s| &s.layer == layer) {
+            if self.surfaces[index].size != configure.new_size {
+                self.surfaces[index].size = configure.new_size;
+                self.draw(index);
+            }
+        }
+    }
+}
+
+impl ShmHandler for Background {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+
+impl ProvidesRegistryState for Background {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![OutputState];
+}
+
+delegate_compositor!(Background);
+delegate_output!(Background);
+delegate_shm!(Background);
+delegate_layer!(Background);
+delegate_registry!(Background);